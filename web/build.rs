@@ -4,7 +4,7 @@ use std::{borrow::Cow, process::Command};
 /// Generate the `cargo:` key output
 pub fn generate_cargo_keys() {
     let output = Command::new("git")
-        .args(&["rev-parse", "--short", "HEAD"])
+        .args(["rev-parse", "--short", "HEAD"])
         .output();
 
     let commit = match output {
@@ -31,7 +31,12 @@ pub fn generate_cargo_keys() {
     println!(
         "cargo:rustc-env=RUST_WEB_DEV_VERSION={}",
         get_version(&commit)
-    )
+    );
+    println!("cargo:rustc-env=RUST_WEB_DEV_COMMIT={}", commit);
+    println!("cargo:rustc-env=RUST_WEB_DEV_PLATFORM={}", get_platform());
+    // Rebuild when HEAD moves, so the commit stays accurate.
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
 }
 
 fn get_platform() -> String {
@@ -1,7 +1,11 @@
 use warp::{
     filters::{body::BodyDeserializeError, cors::CorsForbidden},
-    http::StatusCode,
+    http::{
+        header::{RETRY_AFTER, WWW_AUTHENTICATE},
+        StatusCode,
+    },
     reject::Reject,
+    reply::Response,
     Rejection, Reply,
 };
 
@@ -23,36 +27,96 @@ impl std::fmt::Display for APILayerError {
     }
 }
 
+/// A client sent more requests than its rate limit allows.
+#[derive(Debug, Clone)]
+pub struct RateLimitExceeded {
+    /// Requests allowed in a window.
+    pub limit: u32,
+    /// Seconds until the next request is allowed.
+    pub retry_after_secs: u64,
+}
+
 //  This is a custom error enum to represent various types of errors
 #[derive(Debug)]
 pub enum CustomError {
     ParseError(std::num::ParseIntError),
     MissingParameters,
     WrongPassword,
+    MissingToken,
     CannotDecryptToken,
     Unauthorized,
+    InsufficientScope,
+    NotFound,
+    PreconditionRequired,
+    PreconditionFailed,
     ArgonLibraryError(ArgonError),
     DatabaseQueryError(sqlx::Error),
     MigrationError(sqlx::migrate::MigrateError),
+    ConfigError(String),
+    OidcError(String),
     ReqwestAPIError(ReqwestError),
     MiddlewareReqwestAPIError(MiddlewareReqwestError),
     ClientError(APILayerError),
-    ServerError(APILayerError)
+    ServerError(APILayerError),
+    RateLimited(RateLimitExceeded),
+    PayloadTooLarge(u64),
+    LengthRequired,
+}
+
+impl CustomError {
+    /// Name of the variant, e.g. to count errors by kind.
+    pub fn variant(&self) -> &'static str {
+        match self {
+            CustomError::ParseError(_) => "ParseError",
+            CustomError::MissingParameters => "MissingParameters",
+            CustomError::WrongPassword => "WrongPassword",
+            CustomError::MissingToken => "MissingToken",
+            CustomError::CannotDecryptToken => "CannotDecryptToken",
+            CustomError::Unauthorized => "Unauthorized",
+            CustomError::InsufficientScope => "InsufficientScope",
+            CustomError::NotFound => "NotFound",
+            CustomError::PreconditionRequired => "PreconditionRequired",
+            CustomError::PreconditionFailed => "PreconditionFailed",
+            CustomError::ArgonLibraryError(_) => "ArgonLibraryError",
+            CustomError::DatabaseQueryError(_) => "DatabaseQueryError",
+            CustomError::MigrationError(_) => "MigrationError",
+            CustomError::ConfigError(_) => "ConfigError",
+            CustomError::OidcError(_) => "OidcError",
+            CustomError::ReqwestAPIError(_) => "ReqwestAPIError",
+            CustomError::MiddlewareReqwestAPIError(_) => "MiddlewareReqwestAPIError",
+            CustomError::ClientError(_) => "ClientError",
+            CustomError::ServerError(_) => "ServerError",
+            CustomError::RateLimited(_) => "RateLimited",
+            CustomError::PayloadTooLarge(_) => "PayloadTooLarge",
+            CustomError::LengthRequired => "LengthRequired",
+        }
+    }
 }
 
 impl std::fmt::Display for CustomError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match &*self {
+        match self {
             CustomError::ParseError(err) => {
                 write!(f, "Cannot parse paramenter: {}", err)
             }
             CustomError::MissingParameters => write!(f, "Missing paramenter"),
             CustomError::WrongPassword => write!(f, "Wrong password"),
+            CustomError::MissingToken => write!(f, "Missing token"),
             CustomError::CannotDecryptToken => write!(f, "Cannot decrypt error"),
             CustomError::Unauthorized => write!(
                 f,
                 "No permission to change the underlying resource"
             ),
+            CustomError::InsufficientScope => {
+                write!(f, "Token is missing the required scope")
+            },
+            CustomError::NotFound => write!(f, "Resource not found"),
+            CustomError::PreconditionRequired => {
+                write!(f, "If-Match header required")
+            },
+            CustomError::PreconditionFailed => {
+                write!(f, "Resource was modified by someone else")
+            },
             CustomError::ArgonLibraryError(_) => {
                 write!(f, "Cannot verifiy password")
             },
@@ -60,6 +124,12 @@ impl std::fmt::Display for CustomError {
                 write!(f, "Cannot update, invalid data.")
             },
             CustomError::MigrationError(_) => write!(f, "Cannot migrate data"),
+            CustomError::ConfigError(err) => {
+                write!(f, "Invalid configuration: {}", err)
+            },
+            CustomError::OidcError(err) => {
+                write!(f, "Single sign-on failed: {}", err)
+            },
             CustomError::ReqwestAPIError(err) => {
                 write!(f, "External API error: {}", err)
             },
@@ -72,100 +142,278 @@ impl std::fmt::Display for CustomError {
             CustomError::ServerError(err) => {
                 write!(f, "External Server error: {}", err)
             },
+            CustomError::RateLimited(err) => write!(
+                f,
+                "Too many requests, retry in {} seconds",
+                err.retry_after_secs
+            ),
+            CustomError::PayloadTooLarge(limit) => {
+                write!(f, "Request body is larger than {} bytes", limit)
+            },
+            CustomError::LengthRequired => {
+                write!(f, "Content-Length header required")
+            },
         }
     }
 }
 impl Reject for CustomError {}
 impl Reject for APILayerError {}
-const DUPLICATE_KEY: u32 = 23505;
+/// SQLSTATE of a unique constraint violation.
+const DUPLICATE_KEY: &str = "23505";
+/// SQLSTATE of a query cancelled by the `statement_timeout`.
+const QUERY_CANCELED: &str = "57014";
+/// Unique index on the lowercased email of the accounts.
+const ACCOUNTS_EMAIL_INDEX: &str = "accounts_email_lower_idx";
+
+/// Realm announced in the `WWW-Authenticate` header of 401 responses.
+const AUTH_REALM: &str = "rust_web_dev";
+
+/// Builds a 401 response asking for a Bearer token, as described in
+/// RFC 6750. `error` is set when a token was sent but is not valid.
+fn bearer_challenge(message: &str, error: Option<&str>) -> Response {
+    let challenge = match error {
+        Some(error) => {
+            format!("Bearer realm=\"{}\", error=\"{}\"", AUTH_REALM, error)
+        }
+        None => format!("Bearer realm=\"{}\"", AUTH_REALM),
+    };
+
+    warp::reply::with_header(
+        warp::reply::with_status(message.to_string(), StatusCode::UNAUTHORIZED),
+        WWW_AUTHENTICATE,
+        challenge,
+    )
+    .into_response()
+}
+
+/// Builds the response for a failed query: a taken email is the client's
+/// fault, a query running past the `statement_timeout` is worth a retry,
+/// anything else is ours.
+fn database_error(e: &sqlx::Error) -> Response {
+    let (code, constraint) = match e {
+        sqlx::Error::Database(err) => (err.code(), err.constraint()),
+        _ => (None, None),
+    };
+
+    match (code.as_deref(), constraint) {
+        (Some(DUPLICATE_KEY), Some(ACCOUNTS_EMAIL_INDEX)) => warp::reply::with_status(
+            "Account already exists".to_string(),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ).into_response(),
+        (Some(QUERY_CANCELED), _) => warp::reply::with_status(
+            "Database is busy, retry later".to_string(),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ).into_response(),
+        _ => warp::reply::with_status(
+            "Internal Server Error".to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).into_response(),
+    }
+}
 
 /// This function defines an async function to return an error response for rejected requests
 #[instrument]
-pub async fn return_error(r: Rejection) -> Result<impl Reply, Rejection> {
-    if let Some(crate::CustomError::DatabaseQueryError(e)) = r.find() {
+pub async fn return_error(r: Rejection) -> Result<Response, Rejection> {
+    if let Some(crate::CustomError::MissingToken) = r.find() {
+        event!(Level::WARN, "Missing authentication token");
+        Ok(bearer_challenge("Missing authentication token", None))
+    } else if let Some(crate::CustomError::CannotDecryptToken) = r.find() {
+        event!(Level::WARN, "Invalid authentication token");
+        Ok(bearer_challenge(
+            "Invalid or expired authentication token",
+            Some("invalid_token"),
+        ))
+    } else if let Some(crate::CustomError::RateLimited(e)) = r.find() {
+        event!(Level::WARN, "Rate limit exceeded");
+        let response = warp::reply::with_status(
+            format!("Too many requests, retry in {} seconds", e.retry_after_secs),
+            StatusCode::TOO_MANY_REQUESTS,
+        );
+        let response = warp::reply::with_header(response, RETRY_AFTER, e.retry_after_secs);
+        let response = warp::reply::with_header(response, "ratelimit-limit", e.limit);
+        let response = warp::reply::with_header(response, "ratelimit-remaining", 0);
+        let response =
+            warp::reply::with_header(response, "ratelimit-reset", e.retry_after_secs);
+        Ok(response.into_response())
+    } else if let Some(crate::CustomError::PayloadTooLarge(limit)) = r.find() {
+        event!(Level::WARN, "Request body larger than {} bytes", limit);
+        Ok(warp::reply::with_status(
+            format!("Request body is larger than {} bytes", limit),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ).into_response())
+    } else if let Some(crate::CustomError::LengthRequired) = r.find() {
+        event!(Level::WARN, "Request body without Content-Length");
+        Ok(warp::reply::with_status(
+            "Content-Length header required".to_string(),
+            StatusCode::LENGTH_REQUIRED,
+        ).into_response())
+    } else if let Some(crate::CustomError::DatabaseQueryError(e)) = r.find() {
         event!(Level::ERROR, "Database query error");
-
-        match e {
-            sqlx::Error::Database(err) => {
-                if err.code().unwrap().parse::<u32>().unwrap()
-                    == DUPLICATE_KEY
-                {
-                    Ok(warp::reply::with_status(
-                        "Account already exsists".to_string(),
-                        StatusCode::UNPROCESSABLE_ENTITY,
-                    ))
-                } else {
-                    Ok(warp::reply::with_status(
-                        "Cannot update data".to_string(),
-                        StatusCode::UNPROCESSABLE_ENTITY,
-                    ))
-                }
-            }
-            _ => Ok(warp::reply::with_status(
-                "Cannot update data".to_string(),
-                StatusCode::UNPROCESSABLE_ENTITY,
-            )),
-        }
+        Ok(database_error(e))
     } else if let Some(crate::CustomError::ReqwestAPIError(e)) = r.find() { 
         event!(Level::ERROR, "{}", e);
         Ok(warp::reply::with_status(
             "Internal Server Error".to_string(),
             StatusCode::INTERNAL_SERVER_ERROR,
-        ))
+        ).into_response())
     }  else if let Some(crate::CustomError::Unauthorized) = r.find() {
         event!(Level::ERROR, "Not matching account id");
         Ok(warp::reply::with_status(
             "No permission to change underlying resource".to_string(),
             StatusCode::UNAUTHORIZED,
-        ))
+        ).into_response())
+    } else if let Some(crate::CustomError::InsufficientScope) = r.find() {
+        event!(Level::ERROR, "Token is missing the required scope");
+        Ok(warp::reply::with_status(
+            "Token is missing the required scope".to_string(),
+            StatusCode::FORBIDDEN,
+        ).into_response())
+    } else if let Some(crate::CustomError::NotFound) = r.find() {
+        event!(Level::WARN, "Requested resource was not found");
+        Ok(warp::reply::with_status(
+            "Resource not found".to_string(),
+            StatusCode::NOT_FOUND,
+        ).into_response())
+    } else if let Some(crate::CustomError::PreconditionRequired) = r.find() {
+        event!(Level::WARN, "Edit without If-Match header");
+        Ok(warp::reply::with_status(
+            "If-Match header with the ETag of the resource is required"
+                .to_string(),
+            StatusCode::PRECONDITION_REQUIRED,
+        ).into_response())
+    } else if let Some(crate::CustomError::PreconditionFailed) = r.find() {
+        event!(Level::WARN, "Edit of an outdated version");
+        Ok(warp::reply::with_status(
+            "Resource was modified by someone else, reload it and retry"
+                .to_string(),
+            StatusCode::PRECONDITION_FAILED,
+        ).into_response())
+    } else if let Some(crate::CustomError::OidcError(e)) = r.find() {
+        event!(Level::ERROR, "Single sign-on failed: {}", e);
+        Ok(warp::reply::with_status(
+            "Single sign-on failed".to_string(),
+            StatusCode::UNAUTHORIZED,
+        ).into_response())
     } else if let Some(crate::CustomError::WrongPassword) = r.find() {
         event!(Level::ERROR, "Entered wrong password");
         Ok(warp::reply::with_status(
             "Wrong E-Mail/Password combination".to_string(),
             StatusCode::UNAUTHORIZED,
-        )) 
+        ).into_response()) 
     }
     else if let Some(crate::CustomError::MiddlewareReqwestAPIError(e)) = r.find() {
         event!(Level::ERROR, "{}", e);
         Ok(warp::reply::with_status(
             "Internal Server Error".to_string(),
             StatusCode::INTERNAL_SERVER_ERROR,
-        ))
+        ).into_response())
     } else if let Some(crate::CustomError::ClientError(e)) = r.find() {
         event!(Level::ERROR, "{}", e);
         Ok(warp::reply::with_status(
             "Internal Server Error".to_string(),
             StatusCode::INTERNAL_SERVER_ERROR,
-        ))
+        ).into_response())
     } else if let Some(crate::CustomError::ServerError(e)) = r.find() {
         event!(Level::ERROR, "{}", e);
         Ok(warp::reply::with_status(
             "Internal Server Error".to_string(),
             StatusCode::INTERNAL_SERVER_ERROR,
-        ))
+        ).into_response())
     } else if let Some(error) = r.find::<CorsForbidden>() {
         event!(Level::ERROR, "CORS forbidden error: {}", error);
         Ok(warp::reply::with_status(
             error.to_string(),
             StatusCode::FORBIDDEN,
-        ))
+        ).into_response())
     } else if let Some(error) = r.find::<BodyDeserializeError>() {
         event!(Level::ERROR, "Cannot deserizalize request body: {}", error);
         Ok(warp::reply::with_status(
             error.to_string(),
             StatusCode::UNPROCESSABLE_ENTITY,
-        ))
+        ).into_response())
     } else if let Some(error) = r.find::<CustomError>() {
         event!(Level::ERROR, "{}", error);
         Ok(warp::reply::with_status(
             error.to_string(),
             StatusCode::UNPROCESSABLE_ENTITY,
-        ))
+        ).into_response())
     } else {
         event!(Level::WARN, "Requested route was not found");
         Ok(warp::reply::with_status(
             "Route not found".to_string(),
             StatusCode::NOT_FOUND,
-        ))
+        ).into_response())
+    }
+}
+
+#[cfg(test)]
+mod handle_errors_tests {
+    use super::*;
+    use std::borrow::Cow;
+    use std::error::Error as StdError;
+
+    /// Stands in for the errors of the Postgres driver.
+    #[derive(Debug)]
+    struct PgError {
+        code: &'static str,
+        constraint: Option<&'static str>,
+    }
+
+    impl std::fmt::Display for PgError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "SQLSTATE {}", self.code)
+        }
+    }
+
+    impl StdError for PgError {}
+
+    impl sqlx::error::DatabaseError for PgError {
+        fn message(&self) -> &str {
+            "error"
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.code))
+        }
+
+        fn as_error(&self) -> &(dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn StdError + Send + Sync + 'static> {
+            self
+        }
+
+        fn constraint(&self) -> Option<&str> {
+            self.constraint
+        }
+    }
+
+    fn status(code: &'static str, constraint: Option<&'static str>) -> StatusCode {
+        let e = sqlx::Error::Database(Box::new(PgError { code, constraint }));
+        database_error(&e).status()
+    }
+
+    #[test]
+    fn map_database_errors() {
+        assert_eq!(
+            status(DUPLICATE_KEY, Some(ACCOUNTS_EMAIL_INDEX)),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            status(DUPLICATE_KEY, Some("api_tokens_token_hash_key")),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(status(QUERY_CANCELED, None), StatusCode::SERVICE_UNAVAILABLE);
+        // SQLSTATEs aren't all numbers.
+        assert_eq!(status("22P02", None), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            database_error(&sqlx::Error::PoolTimedOut).status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
     }
 }
@@ -0,0 +1,15 @@
+-- Create api_tokens table for personal access tokens.
+CREATE TABLE IF NOT EXISTS api_tokens (
+    id serial PRIMARY KEY,
+    account_id integer NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    token_prefix VARCHAR(16) NOT NULL,
+    token_hash CHAR(64) NOT NULL UNIQUE,
+    scopes TEXT [] NOT NULL,
+    created_on TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_used_on TIMESTAMPTZ,
+    expires_on TIMESTAMPTZ,
+    revoked_on TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS api_tokens_account_id_idx ON api_tokens (account_id);
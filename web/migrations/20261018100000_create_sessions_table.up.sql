@@ -0,0 +1,13 @@
+-- Create sessions table, one row per issued login token.
+CREATE TABLE IF NOT EXISTS sessions (
+    id uuid PRIMARY KEY,
+    account_id integer NOT NULL,
+    created_on TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_seen_on TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_on TIMESTAMPTZ NOT NULL,
+    user_agent TEXT,
+    ip VARCHAR(45),
+    revoked_on TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS sessions_account_id_idx ON sessions (account_id);
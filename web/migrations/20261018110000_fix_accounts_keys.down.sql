@@ -0,0 +1,12 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS answers_account_id_idx;
+DROP INDEX IF EXISTS questions_account_id_idx;
+
+ALTER TABLE sessions DROP CONSTRAINT IF EXISTS sessions_account_id_fkey;
+ALTER TABLE api_tokens DROP CONSTRAINT IF EXISTS api_tokens_account_id_fkey;
+ALTER TABLE answers DROP CONSTRAINT IF EXISTS answers_account_id_fkey;
+ALTER TABLE questions DROP CONSTRAINT IF EXISTS questions_account_id_fkey;
+
+DROP INDEX IF EXISTS accounts_email_lower_idx;
+ALTER TABLE accounts DROP CONSTRAINT accounts_pkey;
+ALTER TABLE accounts ADD PRIMARY KEY (email);
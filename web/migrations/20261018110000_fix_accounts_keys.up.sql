@@ -0,0 +1,82 @@
+-- Make accounts.id the primary key, emails unique regardless of case, and
+-- turn the account_id columns into real foreign keys.
+
+-- Accounts whose emails only differ by case are different people as far
+-- as we know, so their posts and credentials are never moved. The oldest
+-- account keeps its email, the others are renamed to `email+dup<id>` and
+-- listed, so an operator can sort them out.
+DO $$
+DECLARE
+    renamed text;
+BEGIN
+    SELECT string_agg(a.email || ' (id ' || a.id || ')', ', ' ORDER BY a.id)
+    INTO renamed
+    FROM accounts a
+    WHERE EXISTS (
+        SELECT 1 FROM accounts o
+        WHERE lower(o.email) = lower(a.email) AND o.id < a.id
+    );
+    IF renamed IS NOT NULL THEN
+        RAISE WARNING 'renaming accounts whose emails clash regardless of case: %',
+            renamed;
+    END IF;
+END
+$$;
+
+UPDATE accounts a SET email = a.email || '+dup' || a.id
+WHERE EXISTS (
+    SELECT 1 FROM accounts o
+    WHERE lower(o.email) = lower(a.email) AND o.id < a.id
+);
+
+ALTER TABLE accounts DROP CONSTRAINT accounts_pkey;
+ALTER TABLE accounts ADD PRIMARY KEY (id);
+CREATE UNIQUE INDEX accounts_email_lower_idx ON accounts (lower(email));
+
+-- The account_id columns were serials, so posts may point to accounts
+-- which don't exist. Those are handed to a placeholder account nobody can
+-- log into ("!" is not a valid argon2 hash).
+INSERT INTO accounts (email, password)
+SELECT 'deleted-account@invalid', '!'
+WHERE EXISTS (
+    SELECT 1 FROM questions
+    WHERE account_id NOT IN (SELECT id FROM accounts)
+    UNION ALL
+    SELECT 1 FROM answers
+    WHERE account_id NOT IN (SELECT id FROM accounts)
+)
+ON CONFLICT ((lower(email))) DO NOTHING;
+
+UPDATE questions
+SET account_id = (SELECT id FROM accounts WHERE email = 'deleted-account@invalid')
+WHERE account_id NOT IN (SELECT id FROM accounts);
+UPDATE answers
+SET account_id = (SELECT id FROM accounts WHERE email = 'deleted-account@invalid')
+WHERE account_id NOT IN (SELECT id FROM accounts);
+
+-- Credentials of missing accounts are useless, drop them.
+DELETE FROM api_tokens WHERE account_id NOT IN (SELECT id FROM accounts);
+DELETE FROM sessions WHERE account_id NOT IN (SELECT id FROM accounts);
+
+ALTER TABLE questions ALTER COLUMN account_id DROP DEFAULT;
+ALTER TABLE answers ALTER COLUMN account_id DROP DEFAULT;
+DROP SEQUENCE IF EXISTS questions_account_id_seq;
+DROP SEQUENCE IF EXISTS answers_account_id_seq;
+
+ALTER TABLE questions
+    ALTER COLUMN account_id SET NOT NULL,
+    ADD CONSTRAINT questions_account_id_fkey
+        FOREIGN KEY (account_id) REFERENCES accounts (id);
+ALTER TABLE answers
+    ALTER COLUMN account_id SET NOT NULL,
+    ADD CONSTRAINT answers_account_id_fkey
+        FOREIGN KEY (account_id) REFERENCES accounts (id);
+ALTER TABLE api_tokens
+    ADD CONSTRAINT api_tokens_account_id_fkey
+        FOREIGN KEY (account_id) REFERENCES accounts (id) ON DELETE CASCADE;
+ALTER TABLE sessions
+    ADD CONSTRAINT sessions_account_id_fkey
+        FOREIGN KEY (account_id) REFERENCES accounts (id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS questions_account_id_idx ON questions (account_id);
+CREATE INDEX IF NOT EXISTS answers_account_id_idx ON answers (account_id);
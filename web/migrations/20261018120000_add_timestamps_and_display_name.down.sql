@@ -0,0 +1,9 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS answers_corresponding_question_idx;
+ALTER TABLE accounts DROP COLUMN display_name;
+ALTER TABLE answers DROP COLUMN updated_on;
+ALTER TABLE questions DROP COLUMN updated_on;
+ALTER TABLE answers
+    ALTER COLUMN created_on TYPE TIMESTAMP USING created_on AT TIME ZONE 'UTC';
+ALTER TABLE questions
+    ALTER COLUMN created_on TYPE TIMESTAMP USING created_on AT TIME ZONE 'UTC';
@@ -0,0 +1,27 @@
+-- Timestamps of questions and answers are exposed by the API, store them
+-- with their time zone. Existing values were written by NOW() and are
+-- taken as UTC.
+ALTER TABLE questions
+    ALTER COLUMN created_on TYPE TIMESTAMPTZ USING created_on AT TIME ZONE 'UTC';
+ALTER TABLE answers
+    ALTER COLUMN created_on TYPE TIMESTAMPTZ USING created_on AT TIME ZONE 'UTC';
+
+-- Time of the last edit, the creation time until a post is edited.
+ALTER TABLE questions ADD COLUMN updated_on TIMESTAMPTZ;
+UPDATE questions SET updated_on = created_on;
+ALTER TABLE questions
+    ALTER COLUMN updated_on SET NOT NULL,
+    ALTER COLUMN updated_on SET DEFAULT NOW();
+
+ALTER TABLE answers ADD COLUMN updated_on TIMESTAMPTZ;
+UPDATE answers SET updated_on = created_on;
+ALTER TABLE answers
+    ALTER COLUMN updated_on SET NOT NULL,
+    ALTER COLUMN updated_on SET DEFAULT NOW();
+
+-- Name shown as the author of posts, the part of the email before the @
+-- when not set.
+ALTER TABLE accounts ADD COLUMN display_name VARCHAR(255);
+
+CREATE INDEX IF NOT EXISTS answers_corresponding_question_idx
+    ON answers (corresponding_question);
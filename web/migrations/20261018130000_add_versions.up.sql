@@ -0,0 +1,4 @@
+-- Version of questions and answers, bumped on every edit and sent as
+-- their ETag so concurrent edits can be detected.
+ALTER TABLE questions ADD COLUMN version integer NOT NULL DEFAULT 1;
+ALTER TABLE answers ADD COLUMN version integer NOT NULL DEFAULT 1;
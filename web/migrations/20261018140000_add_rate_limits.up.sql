@@ -0,0 +1,10 @@
+-- Token buckets of the rate limits, shared by the instances of the
+-- server when RATE_LIMIT_STORE is postgres. `tokens` is the content of
+-- the bucket when it was last updated.
+CREATE TABLE IF NOT EXISTS rate_limits (
+    bucket TEXT PRIMARY KEY,
+    tokens DOUBLE PRECISION NOT NULL,
+    allowed BOOLEAN NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS rate_limits_updated_at ON rate_limits (updated_at);
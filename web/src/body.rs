@@ -0,0 +1,145 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use warp::{Filter, Rejection};
+
+use handle_errors::CustomError;
+
+/// Largest request bodies each group of routes reads, in bytes, the
+/// `[body_limit]` section of the configuration file.
+#[derive(clap::Args, Serialize, Clone, Debug, PartialEq)]
+pub struct BodyLimitConfig {
+    /// Bodies of the question routes, asking and editing
+    #[clap(
+        name = "body-limit-questions",
+        long = "body-limit-questions",
+        env = "BODY_LIMIT_QUESTIONS",
+        default_value = "65536"
+    )]
+    pub questions: u64,
+    /// Bodies of the answer routes, answering and editing
+    #[clap(
+        name = "body-limit-answers",
+        long = "body-limit-answers",
+        env = "BODY_LIMIT_ANSWERS",
+        default_value = "16384"
+    )]
+    pub answers: u64,
+    /// Bodies of the registration, login and personal token routes
+    #[clap(
+        name = "body-limit-accounts",
+        long = "body-limit-accounts",
+        env = "BODY_LIMIT_ACCOUNTS",
+        default_value = "4096"
+    )]
+    pub accounts: u64,
+}
+
+impl BodyLimitConfig {
+    /// Checks no limit refuses every body.
+    pub fn validate(&self) -> Result<(), CustomError> {
+        if self.questions == 0 || self.answers == 0 || self.accounts == 0 {
+            return Err(CustomError::ConfigError(
+                "body limits must be at least 1 byte".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Refuses bodies larger than `bytes` before they are read, with 413
+/// Payload Too Large. The `Content-Length` header is required, so chunked
+/// bodies can't get around the limit.
+pub fn limit(bytes: u64) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and_then(move |length: Option<u64>| async move {
+            match length {
+                Some(length) if length <= bytes => Ok(()),
+                Some(_) => Err(warp::reject::custom(CustomError::PayloadTooLarge(bytes))),
+                None => Err(warp::reject::custom(CustomError::LengthRequired)),
+            }
+        })
+        .untuple_one()
+}
+
+/// Reads a json body, or an x-www-form-urlencoded one for the clients
+/// which sent forms before json was accepted.
+pub fn json_or_form<T: DeserializeOwned + Send>(
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    warp::body::json().or(warp::body::form()).unify()
+}
+
+#[cfg(test)]
+mod body_tests {
+    use super::*;
+    use handle_errors::return_error;
+
+    fn routes() -> impl Filter<Extract = (impl warp::Reply,), Error = Rejection> + Clone {
+        warp::post()
+            .and(limit(16))
+            .and(warp::body::json())
+            .map(|body: serde_json::Value| warp::reply::json(&body))
+            .recover(return_error)
+    }
+
+    #[tokio::test]
+    async fn refuse_large_bodies() {
+        let response = warp::test::request()
+            .method("POST")
+            .json(&"short")
+            .reply(&routes())
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request()
+            .method("POST")
+            .json(&"far too long for the limit")
+            .reply(&routes())
+            .await;
+        assert_eq!(response.status(), 413);
+        assert_eq!(response.body(), "Request body is larger than 16 bytes");
+
+        let response = warp::test::request()
+            .method("POST")
+            .header("transfer-encoding", "chunked")
+            .reply(&routes())
+            .await;
+        assert_eq!(response.status(), 411);
+    }
+
+    #[tokio::test]
+    async fn accept_json_and_form() {
+        #[derive(serde::Deserialize)]
+        struct NewAnswer {
+            content: String,
+            question_id: i32,
+        }
+        let routes = json_or_form().map(|answer: NewAnswer| {
+            format!("{} {}", answer.question_id, answer.content)
+        });
+
+        let response = warp::test::request()
+            .method("POST")
+            .json(&serde_json::json!({"content": "hello", "question_id": 2}))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.body(), "2 hello");
+
+        let response = warp::test::request()
+            .method("POST")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("content=hello&question_id=2")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.body(), "2 hello");
+    }
+
+    #[test]
+    fn refuse_zero_limits() {
+        let config = BodyLimitConfig {
+            questions: 65536,
+            answers: 0,
+            accounts: 4096,
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::io::Write;
+use warp::http::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY};
+use warp::hyper::body::{Bytes, HttpBody};
+use warp::{reply::Response, Filter, Rejection, Reply};
+
+/// Brotli quality, a fast setting still smaller than gzip.
+const BROTLI_QUALITY: u32 = 5;
+
+/// Brotli window, as a power of two.
+const BROTLI_WINDOW: u32 = 22;
+
+/// Compression of the list responses, the `[compression]` section of the
+/// configuration file.
+#[derive(clap::Args, Serialize, Clone, Debug, PartialEq)]
+pub struct CompressionConfig {
+    /// Compress list responses with gzip or brotli, when the client
+    /// accepts it
+    #[clap(
+        name = "compression-enabled",
+        long = "compression-enabled",
+        env = "COMPRESSION_ENABLED",
+        default_value = "true",
+        parse(try_from_str)
+    )]
+    pub enabled: bool,
+    /// Smallest response compressed, in bytes
+    #[clap(
+        name = "compression-min-bytes",
+        long = "compression-min-bytes",
+        env = "COMPRESSION_MIN_BYTES",
+        default_value = "1024"
+    )]
+    pub min_bytes: u64,
+}
+
+impl CompressionConfig {
+    /// Picks the encoding of a response from the `Accept-Encoding` header
+    /// of its request.
+    pub fn negotiate(&self) -> impl Filter<Extract = (Compression,), Error = Infallible> + Clone {
+        let config = self.clone();
+        warp::header::optional::<String>(ACCEPT_ENCODING.as_str())
+            .or(warp::any().map(|| None))
+            .unify()
+            .map(move |accept_encoding: Option<String>| Compression {
+                encoding: match accept_encoding {
+                    Some(accept_encoding) if config.enabled => {
+                        Encoding::negotiate(&accept_encoding)
+                    }
+                    _ => Encoding::Identity,
+                },
+                min_bytes: config.min_bytes,
+            })
+    }
+}
+
+/// Content codings the server can send.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl Encoding {
+    /// The coding of an `Accept-Encoding` header with the highest weight,
+    /// brotli on a tie.
+    pub fn negotiate(accept_encoding: &str) -> Encoding {
+        let mut weights = Vec::new();
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';').map(str::trim);
+            let coding = params.next().unwrap_or_default().to_ascii_lowercase();
+            let weight = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            weights.push((coding, weight));
+        }
+        let weight = |coding: &str| {
+            weights
+                .iter()
+                .find(|(name, _)| name == coding)
+                .or_else(|| weights.iter().find(|(name, _)| name == "*"))
+                .map_or(0.0, |(_, weight)| *weight)
+        };
+
+        let (brotli, gzip) = (weight("br"), weight("gzip"));
+        if brotli > 0.0 && brotli >= gzip {
+            Encoding::Brotli
+        } else if gzip > 0.0 {
+            Encoding::Gzip
+        } else {
+            Encoding::Identity
+        }
+    }
+
+    fn name(self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Identity => None,
+        }
+    }
+
+    fn encode(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut encoder =
+                    brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                encoder.write_all(body)?;
+                Ok(encoder.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Encoding::Identity => Ok(body.to_vec()),
+        }
+    }
+}
+
+/// How a response is to be compressed, see `CompressionConfig::negotiate`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Compression {
+    encoding: Encoding,
+    min_bytes: u64,
+}
+
+/// Compresses a reply in the negotiated encoding, when its body is large
+/// enough. Streamed bodies are left alone.
+pub async fn compress(compression: Compression, reply: impl Reply) -> Result<Response, Rejection> {
+    let mut response = reply.into_response();
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+    let name = match compression.encoding.name() {
+        Some(name) if !response.headers().contains_key(CONTENT_ENCODING) => name,
+        _ => return Ok(response),
+    };
+    match response.body().size_hint().exact() {
+        Some(size) if size >= compression.min_bytes => {}
+        _ => return Ok(response),
+    }
+
+    let (mut parts, body) = response.into_parts();
+    // The body is in memory, as its size is known.
+    let body = warp::hyper::body::to_bytes(body).await.unwrap_or_default();
+    let encoding = compression.encoding;
+    let plain = body.clone();
+    let encoded = tokio::task::spawn_blocking(move || encoding.encode(&plain))
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+    let body = match encoded {
+        Ok(encoded) => {
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(name));
+            parts.headers.remove(CONTENT_LENGTH);
+            Bytes::from(encoded)
+        }
+        Err(e) => {
+            tracing::event!(tracing::Level::ERROR, "cannot compress response: {:?}", e);
+            body
+        }
+    };
+    Ok(Response::from_parts(parts, body.into()))
+}
+
+#[cfg(test)]
+mod compression_tests {
+    use super::*;
+    use std::io::Read;
+
+    fn config() -> CompressionConfig {
+        CompressionConfig {
+            enabled: true,
+            min_bytes: 64,
+        }
+    }
+
+    fn routes(
+        config: CompressionConfig,
+        body: String,
+    ) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+        config
+            .negotiate()
+            .and(warp::any().map(move || body.clone()))
+            .and_then(compress)
+    }
+
+    #[test]
+    fn negotiate_encodings() {
+        assert_eq!(Encoding::negotiate("gzip, deflate, br"), Encoding::Brotli);
+        assert_eq!(Encoding::negotiate("gzip"), Encoding::Gzip);
+        assert_eq!(Encoding::negotiate("br;q=0.5, gzip;q=0.8"), Encoding::Gzip);
+        assert_eq!(Encoding::negotiate("br;q=0, *"), Encoding::Gzip);
+        assert_eq!(Encoding::negotiate("*;q=0.3"), Encoding::Brotli);
+        assert_eq!(Encoding::negotiate("identity"), Encoding::Identity);
+        assert_eq!(Encoding::negotiate("deflate, *;q=0"), Encoding::Identity);
+    }
+
+    #[tokio::test]
+    async fn compress_large_bodies() {
+        let body = "question ".repeat(100);
+
+        let response = warp::test::request()
+            .header("accept-encoding", "gzip")
+            .reply(&routes(config(), body.clone()))
+            .await;
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.headers()["vary"], "accept-encoding");
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&response.body()[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+
+        let response = warp::test::request()
+            .header("accept-encoding", "br")
+            .reply(&routes(config(), body.clone()))
+            .await;
+        assert_eq!(response.headers()["content-encoding"], "br");
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&response.body()[..], 4096)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+    }
+
+    #[tokio::test]
+    async fn leave_small_bodies_alone() {
+        let small = warp::test::request()
+            .header("accept-encoding", "gzip")
+            .reply(&routes(config(), "short".to_string()))
+            .await;
+        assert!(!small.headers().contains_key("content-encoding"));
+
+        let unaccepted = warp::test::request()
+            .reply(&routes(config(), "question ".repeat(100)))
+            .await;
+        assert!(!unaccepted.headers().contains_key("content-encoding"));
+
+        let disabled = CompressionConfig {
+            enabled: false,
+            ..config()
+        };
+        let response = warp::test::request()
+            .header("accept-encoding", "gzip")
+            .reply(&routes(disabled, "question ".repeat(100)))
+            .await;
+        assert!(!response.headers().contains_key("content-encoding"));
+    }
+}
@@ -1,28 +1,38 @@
-use warp::http::StatusCode;
 use handle_errors::CustomError;
 use std::collections::HashMap;
+use crate::metrics;
 use crate::store::Store;
 use crate::types::account::Session;
-use crate::types::answer::NewAnswer;
+use crate::types::api_token::Scope;
+use crate::controllers::authentication::require_scope;
+use crate::controllers::precondition::{
+    cached_json, created, versioned_json, Conditions, IfMatch,
+};
+use crate::types::answer::{AnswerUpdate, NewAnswer};
 use crate::types::pagination::Pagination;
-use tracing::{instrument, info};
+use tracing::instrument;
 use tracing::{event, Level};
 
 use crate::types::pagination::extract_pagination;
 
 /// Add an answer to a question from `/answers` route
 /// # Example query
-/// POST requests to this route, with the body format is
-/// x-www-form-urlendcoded with two key-value:
-/// content: hellomn
-/// question_id: 2
+/// POST requests to this route, with the body format is json:
+///```
+/// {
+///     "content": "hellomn",
+///     "question_id": 2
+/// }
+///```
+/// x-www-form-urlencoded bodies with the same keys are still accepted.
+/// The reply is 201 Created with the answer and its `Location`.
 #[instrument]
 pub async fn add_answer(
     session: Session,
     store: Store,
     new_answer: NewAnswer,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-
+    require_scope(&session, Scope::WriteAnswers)?;
     let account_id = session.account_id;
     let answer = NewAnswer {
         content: new_answer.content,
@@ -30,12 +40,49 @@ pub async fn add_answer(
     };
 
     match store.add_answer(answer, account_id).await {
-        Ok(_) => {
-            Ok(warp::reply::with_status("Answer added", StatusCode::OK))
+        Ok(answer) => {
+            metrics::answer_created();
+            Ok(created(
+                versioned_json(&answer, answer.version),
+                format!("/answers/{}", answer.id.0),
+            ))
         }
         Err(e) => Err(warp::reject::custom(e)),
     }
 }
+/// Edit an answer from the `/answers/answer_id` route
+/// # Example query
+/// PUT requests to this route, with the `If-Match` header set to the
+/// `ETag` of the answer (its `version`) and the body format is json:
+///```
+/// {
+///     "content": "hellomn"
+/// }
+///```
+#[instrument]
+pub async fn update_answer(
+    id: i32,
+    session: Session,
+    if_match: IfMatch,
+    store: Store,
+    answer: AnswerUpdate,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    require_scope(&session, Scope::WriteAnswers)?;
+    let account_id = session.account_id;
+    if store.is_answer_owner(id, &account_id).await? {
+        match store
+            .update_answer(answer, id, account_id, if_match.versions())
+            .await
+        {
+            Ok(Some(res)) => Ok(versioned_json(&res, res.version)),
+            Ok(None) => Err(warp::reject::custom(CustomError::PreconditionFailed)),
+            Err(e) => Err(warp::reject::custom(e)),
+        }
+    } else {
+        Err(warp::reject::custom(CustomError::Unauthorized))
+    }
+}
+
 /// This function gets answers to a specific question from '/answer' route
 /// # Example query
 /// GET requests to this route, with the query params:
@@ -43,9 +90,12 @@ pub async fn add_answer(
 /// ```
 /// /answers?limit=10&offset=0&question_id=1
 /// ```
+/// The reply carries a weak `ETag`, and is answered with 304 when the
+/// client's copy is still current.
 #[instrument]
 pub async fn get_question_answers(
     params: HashMap<String, String>,
+    conditions: Conditions,
     store: Store,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     event!(target: "web", Level::INFO, "querying question's answers");
@@ -61,8 +111,129 @@ pub async fn get_question_answers(
     
     match store.get_question_answers(pagination.limit, pagination.offset, question_id).await
     {
-        Ok(res) => Ok(warp::reply::json(&res)),
+        Ok(res) => Ok(cached_json(&res, None, None, false, &conditions)),
         Err(e) => Err(warp::reject::custom(e)),
     }
 }
 
+
+/// This function gets a single answer from the `/answers/answer_id` route,
+/// the `Location` of a posted answer.
+/// The reply carries `ETag` and `Last-Modified`, and is answered with 304
+/// when the client's copy is still current.
+#[instrument]
+pub async fn get_answer(
+    id: i32,
+    conditions: Conditions,
+    store: Store,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match store.get_answer(id).await? {
+        Some(answer) => Ok(cached_json(
+            &answer,
+            Some(answer.version),
+            Some(answer.updated_at),
+            false,
+            &conditions,
+        )),
+        None => Err(warp::reject::custom(CustomError::NotFound)),
+    }
+}
+
+#[cfg(test)]
+mod answer_tests {
+    use super::*;
+    use crate::types::account::{Account, Session};
+    use crate::types::question::NewQuestion;
+    use chrono::{Duration, Utc};
+    use warp::Filter;
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL, run with --ignored"]
+    async fn follow_location_of_posted_answers() {
+        let store = Store::test_database().await;
+
+        let email = format!("location-{}@test.invalid", uuid::Uuid::new_v4());
+        let account = store
+            .clone()
+            .add_account(Account {
+                id: None,
+                email: email.clone(),
+                password: "!".to_string(),
+                display_name: None,
+            })
+            .await
+            .unwrap();
+        let question = store
+            .clone()
+            .add_question(
+                NewQuestion {
+                    title: "location".to_string(),
+                    content: "location".to_string(),
+                    tags: None,
+                },
+                account.id.clone(),
+            )
+            .await
+            .unwrap();
+        let session = Session {
+            exp: Utc::now() + Duration::days(1),
+            account_id: account.id.clone(),
+            nbf: Utc::now(),
+            scopes: Scope::all(),
+            api_token_id: None,
+            session_id: None,
+        };
+
+        let store_filter = warp::any().map({
+            let store = store.clone();
+            move || store.clone()
+        });
+        let post = warp::post()
+            .and(warp::path("answers"))
+            .and(warp::any().map(move || session.clone()))
+            .and(store_filter.clone())
+            .and(warp::body::json())
+            .and_then(add_answer);
+        let get = warp::get()
+            .and(warp::path("answers"))
+            .and(warp::path::param::<i32>())
+            .and(crate::controllers::precondition::conditions())
+            .and(store_filter)
+            .and_then(get_answer);
+        let routes = post.or(get).recover(handle_errors::return_error);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/answers")
+            .json(&serde_json::json!({"content": "here", "question_id": question.id.0}))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 201);
+        let location = response.headers()["location"].to_str().unwrap().to_string();
+
+        let response = warp::test::request()
+            .path(&location)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        let answer: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(answer["content"], "here");
+        assert_eq!(answer["question_id"], question.id.0);
+
+        sqlx::query("DELETE FROM answers WHERE corresponding_question = $1")
+            .bind(question.id.0)
+            .execute(&store.connection)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM questions WHERE id = $1")
+            .bind(question.id.0)
+            .execute(&store.connection)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM accounts WHERE id = $1")
+            .bind(account.id.0)
+            .execute(&store.connection)
+            .await
+            .unwrap();
+    }
+}
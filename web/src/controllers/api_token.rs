@@ -0,0 +1,112 @@
+use chrono::prelude::*;
+use tracing::instrument;
+use warp::http::StatusCode;
+
+use crate::controllers::authentication::{
+    generate_api_token, hash_api_token, require_scope,
+};
+use crate::controllers::precondition::created;
+use crate::store::Store;
+use crate::types::account::Session;
+use crate::types::api_token::{CreatedApiToken, NewApiToken, Scope};
+
+/// Number of leading characters of a token kept for display.
+const API_TOKEN_DISPLAY_LEN: usize = 12;
+
+/// Create a personal access token from the `/me/tokens` route
+/// # Example query
+/// POST requests to this route, with a login token in headers and the
+/// body format is json:
+/// ```
+/// {
+///     "name": "deploy bot",
+///     "scopes": ["read", "write_answers"],
+///     "expires_in_days": 30
+/// }
+/// ```
+/// The response contains the token, which is never shown again.
+#[instrument]
+pub async fn add_api_token(
+    session: Session,
+    store: Store,
+    new_token: NewApiToken,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // A token must not be able to mint or widen other tokens.
+    if session.api_token_id.is_some() {
+        return Err(warp::reject::custom(
+            handle_errors::CustomError::InsufficientScope,
+        ));
+    }
+
+    let token = generate_api_token();
+    let prefix = token.chars().take(API_TOKEN_DISPLAY_LEN).collect();
+    let expires_at = new_token
+        .expires_in_days
+        .map(|days| Utc::now() + chrono::Duration::days(i64::from(days)));
+
+    match store
+        .add_api_token(
+            session.account_id,
+            new_token.name,
+            new_token.scopes,
+            prefix,
+            hash_api_token(&token),
+            expires_at,
+        )
+        .await
+    {
+        Ok(details) => {
+            let location = format!("/me/tokens/{}", details.id.0);
+            Ok(created(
+                warp::reply::json(&CreatedApiToken { token, details }),
+                location,
+            ))
+        }
+        Err(e) => Err(warp::reject::custom(e)),
+    }
+}
+
+/// List the active personal access tokens from the `/me/tokens` route
+#[instrument]
+pub async fn get_api_tokens(
+    session: Session,
+    store: Store,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    require_scope(&session, Scope::Read)?;
+
+    match store.get_api_tokens(session.account_id).await {
+        Ok(tokens) => Ok(warp::reply::json(&tokens)),
+        Err(e) => Err(warp::reject::custom(e)),
+    }
+}
+
+/// Revoke a personal access token from the `/me/tokens/token_id` route
+/// # Example query
+/// DELETE requests to this route, with the query is
+/// the id of the token we want to revoke
+///```
+/// /me/tokens/2
+///```
+#[instrument]
+pub async fn revoke_api_token(
+    id: i32,
+    session: Session,
+    store: Store,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if session.api_token_id.is_some() {
+        return Err(warp::reject::custom(
+            handle_errors::CustomError::InsufficientScope,
+        ));
+    }
+
+    match store.revoke_api_token(id, session.account_id).await {
+        Ok(true) => Ok(warp::reply::with_status(
+            format!("Token {} revoked", id),
+            StatusCode::OK,
+        )),
+        Ok(false) => Err(warp::reject::custom(
+            handle_errors::CustomError::Unauthorized,
+        )),
+        Err(e) => Err(warp::reject::custom(e)),
+    }
+}
@@ -1,11 +1,21 @@
-use argon2::{self, Config};
 use chrono::prelude::*;
 use rand::Rng;
-use std::{env, future};
-use warp::{http::StatusCode, Filter};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use warp::Filter;
 
+use crate::controllers::precondition::created;
+use crate::key_ring::{
+    footer_for, raw_footer, KeyRing, PublishedKeySet, TokenFooter, TokenKind,
+};
+use crate::metrics;
+use crate::password::PasswordHasher;
 use crate::store::Store;
-use crate::types::account::{Account, AccountId, Session};
+use crate::types::account::{Account, AccountId, ClientInfo, Session, SessionId};
+use crate::types::api_token::{Scope, API_TOKEN_PREFIX};
+
+/// How long a login token stays valid.
+const SESSION_LIFETIME_DAYS: i64 = 1;
 
 /// This function handles the registration process for 
 ///the '/register' route.
@@ -15,29 +25,48 @@ use crate::types::account::{Account, AccountId, Session};
 /// ```
 /// {
 ///      "email": "vanhg@gmail.com",
-///      "password": "1"
+///      "password": "1",
+///      "display_name": "VanhG"
 /// }
 /// ```
+/// `display_name` is optional. The reply is 201 Created with the account,
+/// without its password.
 pub async fn register(
     store: Store,
+    hasher: PasswordHasher,
     account: Account,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let hashed_password = hash_password(account.password.as_bytes());
+    let hashed_password = hasher.hash(account.password.into_bytes()).await?;
 
     let account = Account {
         id: account.id,
         email: account.email,
         password: hashed_password,
+        display_name: account.display_name,
     };
 
     match store.add_account(account).await {
-        Ok(_) => {
-            Ok(warp::reply::with_status("Account added", StatusCode::OK))
+        Ok(account) => {
+            metrics::account_registered();
+            Ok(created(
+                warp::reply::json(&account),
+                format!("/accounts/{}", account.id.0),
+            ))
         }
         Err(e) => Err(warp::reject::custom(e)),
     }
 }
 
+/// This function gets the public profile of an account, its id and display
+/// name, from the `/accounts/account_id` route, the `Location` of a
+/// registered account.
+pub async fn get_account(id: i32, store: Store) -> Result<impl warp::Reply, warp::Rejection> {
+    match store.get_author(id).await? {
+        Some(author) => Ok(warp::reply::json(&author)),
+        None => Err(warp::reject::custom(handle_errors::CustomError::NotFound)),
+    }
+}
+
 /// This function handles the login process for 
 ///the '/login' route.
 /// # Example query
@@ -52,104 +81,567 @@ pub async fn register(
 /// The response is the token we use to authentication.
 pub async fn login(
     store: Store,
+    hasher: PasswordHasher,
+    key_ring: KeyRing,
+    client: ClientInfo,
     login: Account,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    match store.get_account(login.email).await {
-        Ok(account) => match verify_password(
-            &account.password,
-            login.password.as_bytes(),
-        ) {
-            Ok(verified) => {
-                if verified {
-                    Ok(warp::reply::json(&issue_token(
-                        account.id.expect("id not found"),
-                    )))
-                } else {
-                    Err(warp::reject::custom(
-                        handle_errors::CustomError::WrongPassword,
-                    ))
-                }
+    let account = store.clone().get_account(login.email).await?;
+    let account_id = account.id.expect("id not found");
+
+    if !hasher
+        .verify(account.password.clone(), login.password.clone().into_bytes())
+        .await?
+    {
+        return Err(warp::reject::custom(
+            handle_errors::CustomError::WrongPassword,
+        ));
+    }
+
+    // Upgrade hashes made with older argon2 settings while we know the
+    // password. A failure here must not prevent the login.
+    if hasher.needs_rehash(&account.password) {
+        let rehashed = match hasher.hash(login.password.into_bytes()).await {
+            Ok(hash) => {
+                store
+                    .clone()
+                    .update_account_password(account_id.clone(), hash)
+                    .await
             }
-            Err(e) => Err(warp::reject::custom(
-                handle_errors::CustomError::ArgonLibraryError(e),
-            )),
-        },
-        Err(e) => Err(warp::reject::custom(e)),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = rehashed {
+            tracing::event!(
+                tracing::Level::WARN,
+                "password rehash failed: {:?}",
+                e
+            );
+        }
     }
+
+    let token = start_session(store, account_id, client, &key_ring).await?;
+    Ok(warp::reply::json(&token))
 }
 
 /// This function verifies the authenticity of a token
-/// provided by user.
+/// provided by user. Both v2.local and v2.public tokens are accepted. The
+/// key is picked from the key ring by the id in the token footer; tokens
+/// issued before footers existed are checked against the current key.
 pub fn verify_token(
     token: String,
+    key_ring: &KeyRing,
 ) -> Result<Session, handle_errors::CustomError> {
-    let key = env::var("PASETO_KEY").unwrap();
-    let token = paseto::tokens::validate_local_token(
-        &token,
-        None,
-        key.as_bytes(),
-        &paseto::tokens::TimeBackend::Chrono,
-    )
+    let footer = raw_footer(&token);
+    let kid = match &footer {
+        Some(footer) => Some(
+            serde_json::from_str::<TokenFooter>(footer)
+                .map_err(|_| handle_errors::CustomError::CannotDecryptToken)?
+                .kid,
+        ),
+        None => None,
+    };
+
+    let token = if token.starts_with("v2.public.") {
+        let key = kid
+            .and_then(|kid| key_ring.find_public(&kid))
+            .ok_or(handle_errors::CustomError::CannotDecryptToken)?;
+        paseto::tokens::validate_public_token(
+            &token,
+            footer.as_deref(),
+            &paseto::tokens::PasetoPublicKey::ED25519PublicKey(key.as_bytes()),
+            &paseto::tokens::TimeBackend::Chrono,
+        )
+    } else {
+        let key = match kid {
+            Some(kid) => key_ring
+                .find(&kid)
+                .ok_or(handle_errors::CustomError::CannotDecryptToken)?,
+            None => key_ring.signing_key(),
+        };
+        paseto::tokens::validate_local_token(
+            &token,
+            footer.as_deref(),
+            key.as_bytes(),
+            &paseto::tokens::TimeBackend::Chrono,
+        )
+    }
     .map_err(|_| handle_errors::CustomError::CannotDecryptToken)?;
 
     serde_json::from_value::<Session>(token)
         .map_err(|_| handle_errors::CustomError::CannotDecryptToken)
 }
 
-/// This function hash the password.
-fn hash_password(password: &[u8]) -> String {
-    let salt = rand::thread_rng().gen::<[u8; 32]>();
-    let config = Config::default();
-    argon2::hash_encoded(password, &salt, &config).unwrap()
+/// This function verifies a personal access token against the hashes
+/// stored in the database.
+pub async fn verify_api_token(
+    store: Store,
+    token: String,
+) -> Result<Session, handle_errors::CustomError> {
+    match store.get_api_token_session(hash_api_token(&token)).await? {
+        Some(session) => Ok(session),
+        None => Err(handle_errors::CustomError::CannotDecryptToken),
+    }
+}
+
+/// This function generates a new random personal access token.
+pub fn generate_api_token() -> String {
+    let secret = rand::thread_rng().gen::<[u8; 32]>();
+    format!("{}{}", API_TOKEN_PREFIX, hex::encode(secret))
+}
+
+/// This function hashes a personal access token for storage. The tokens
+/// are random, so a fast hash is enough.
+pub fn hash_api_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
 }
 
-/// This function verifies a password against a hash using argon2
-fn verify_password(
-    hash: &str,
-    password: &[u8], 
-) -> Result<bool, argon2::Error> {
-    argon2::verify_encoded(hash, password)
+/// This function records a new login session for the account and issues
+/// its token.
+pub async fn start_session(
+    store: Store,
+    account_id: AccountId,
+    client: ClientInfo,
+    key_ring: &KeyRing,
+) -> Result<String, handle_errors::CustomError> {
+    let expires_at = Utc::now() + chrono::Duration::days(SESSION_LIFETIME_DAYS);
+    let session_id = store
+        .add_session(account_id.clone(), client, expires_at)
+        .await?;
+
+    Ok(issue_token(account_id, Some(session_id), &expires_at, key_ring))
 }
 
-/// This function issues a token for authentication. 
-fn issue_token(account_id: AccountId) -> String {
-    let key = env::var("PASETO_KEY").unwrap();
-    let current_date_time = Utc::now();
-    let dt = current_date_time + chrono::Duration::days(1);
+/// This function issues a token for authentication. Depending on the
+/// configuration it is a v2.local token encrypted with the current key of
+/// the key ring, or a v2.public token signed with its Ed25519 key.
+pub fn issue_token(
+    account_id: AccountId,
+    session_id: Option<SessionId>,
+    dt: &DateTime<Utc>,
+    key_ring: &KeyRing,
+) -> String {
+    let signing_key = match key_ring.token_kind() {
+        TokenKind::Public => key_ring.public_signing_key(),
+        TokenKind::Local => None,
+    };
+    let footer = match signing_key {
+        Some(signing_key) => footer_for(&signing_key.id),
+        None => footer_for(&key_ring.signing_key().id),
+    };
 
-    paseto::tokens::PasetoBuilder::new()
-        .set_encryption_key(&Vec::from(key.as_bytes()))
-        .set_expiration(&dt)
+    let mut builder = paseto::tokens::PasetoBuilder::new();
+    let builder = match signing_key {
+        Some(signing_key) => builder.set_ed25519_key(signing_key.key_pair()),
+        None => builder.set_encryption_key(key_ring.signing_key().as_bytes()),
+    };
+
+    let builder = match session_id {
+        Some(session_id) => {
+            builder.set_claim("session_id", serde_json::json!(session_id))
+        }
+        None => builder,
+    };
+
+    builder
+        .set_footer(&footer)
+        .set_expiration(dt)
         .set_not_before(&Utc::now())
         .set_claim("account_id", serde_json::json!(account_id))
         .build()
         .expect("Failed to construct paseto token w/ builder!")
 }
 
-/// Authentication filter for routes requiring authorization
+/// This function publishes the public keys verifying v2.public tokens on
+/// the `/.well-known/paseto-keys` route, so other services can check
+/// who the user is without sharing `PASETO_KEY`.
+/// # Example response
+/// ```
+/// {
+///     "keys": [{
+///         "kid": "2024-02-public",
+///         "kty": "OKP",
+///         "crv": "Ed25519",
+///         "alg": "v2.public",
+///         "x": "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo"
+///     }]
+/// }
+/// ```
+pub async fn get_public_keys(
+    key_ring: KeyRing,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let keys = PublishedKeySet {
+        keys: key_ring
+            .public_keys()
+            .iter()
+            .map(|key| key.to_published())
+            .collect(),
+    };
+
+    Ok(warp::reply::json(&keys))
+}
+
+/// Authentication filter for routes requiring authorization.
+/// Accepts PASETO session tokens as well as personal access tokens, sent
+/// as `Authorization: Bearer <token>`. Missing or invalid tokens are
+/// rejected, which `return_error` answers with a 401.
 pub fn auth(
+    store: Store,
+    key_ring: KeyRing,
 ) -> impl Filter<Extract = (Session,), Error = warp::Rejection> + Clone {
-    warp::header::<String>("Authorization").and_then(|token: String| {
-        let token = match verify_token(token) {
-            Ok(t) => t,
-            Err(_) => return future::ready(Err(warp::reject::reject())),
-        };
-
-        future::ready(Ok(token))
+    optional_auth(store, key_ring).and_then(|session: Option<Session>| async move {
+        session.ok_or_else(|| {
+            warp::reject::custom(handle_errors::CustomError::MissingToken)
+        })
     })
 }
 
+/// Authentication filter for public routes which personalise their
+/// response for logged in users. Extracts `None` when no token is sent,
+/// but still rejects invalid tokens.
+pub fn optional_auth(
+    store: Store,
+    key_ring: KeyRing,
+) -> impl Filter<Extract = (Option<Session>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("Authorization").and_then(
+        move |header: Option<String>| {
+            let store = store.clone();
+            let key_ring = key_ring.clone();
+            async move {
+                let token = match header {
+                    Some(header) => bearer_token(&header)?,
+                    None => return Ok(None),
+                };
+
+                let session = if token.starts_with(API_TOKEN_PREFIX) {
+                    verify_api_token(store, token).await
+                } else {
+                    match verify_token(token, &key_ring) {
+                        Ok(session) => check_session(store, session).await,
+                        Err(e) => Err(e),
+                    }
+                };
+
+                if let Ok(session) = &session {
+                    tracing::Span::current().record("account_id", session.account_id.0);
+                }
+                session.map(Some).map_err(warp::reject::custom)
+            }
+        },
+    )
+}
+
+/// Refuses tokens whose login session was signed out, and records that
+/// the session was seen.
+async fn check_session(
+    store: Store,
+    session: Session,
+) -> Result<Session, handle_errors::CustomError> {
+    match session.session_id {
+        Some(session_id) => {
+            if store.touch_session(session_id).await? {
+                Ok(session)
+            } else {
+                Err(handle_errors::CustomError::CannotDecryptToken)
+            }
+        }
+        None => Ok(session),
+    }
+}
+
+/// Filter extracting the user agent and IP address of the client.
+pub fn client_info(
+) -> impl Filter<Extract = (ClientInfo,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("user-agent")
+        .and(warp::addr::remote())
+        .map(|user_agent: Option<String>, addr: Option<SocketAddr>| ClientInfo {
+            user_agent,
+            ip: addr.map(|addr| addr.ip().to_string()),
+        })
+}
+
+/// Extracts the token from an `Authorization` header. The standard
+/// `Bearer` scheme is expected, but a bare token is still accepted for
+/// older clients.
+fn bearer_token(header: &str) -> Result<String, warp::Rejection> {
+    let header = header.trim();
+    let token = match header.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+            token.trim()
+        }
+        Some(_) => "",
+        None if header.eq_ignore_ascii_case("bearer") => "",
+        None => header,
+    };
+
+    if token.is_empty() {
+        Err(warp::reject::custom(
+            handle_errors::CustomError::CannotDecryptToken,
+        ))
+    } else {
+        Ok(token.to_string())
+    }
+}
+
+/// Rejects the request when the session was not granted the scope.
+pub fn require_scope(
+    session: &Session,
+    scope: Scope,
+) -> Result<(), warp::Rejection> {
+    if session.has_scope(scope) {
+        Ok(())
+    } else {
+        Err(warp::reject::custom(
+            handle_errors::CustomError::InsufficientScope,
+        ))
+    }
+}
+
 #[cfg(test)]
 mod authentication_tests {
-    use super::{auth, env, issue_token, AccountId};
+    use warp::Filter;
+    use super::{
+        auth, bearer_token, hash_api_token, generate_api_token, issue_token,
+        optional_auth, verify_token, AccountId, DateTime, KeyRing, Scope,
+        Store, Utc, API_TOKEN_PREFIX,
+    };
+    use crate::key_ring::{PasetoKey, SigningKey};
+
+    fn key_ring(id: &str, key: &[u8], previous: Vec<PasetoKey>) -> KeyRing {
+        KeyRing::new(PasetoKey::new(id, key).unwrap(), previous, None, Vec::new())
+            .unwrap()
+    }
+
+    fn tomorrow() -> DateTime<Utc> {
+        Utc::now() + chrono::Duration::days(1)
+    }
+
+    fn test_key_ring() -> KeyRing {
+        key_ring("k1", b"vanhg dep trai vanhg dep trai vv", Vec::new())
+    }
+
     #[tokio::test]
     async fn post_questions_auth() {
-        env::set_var("PASETO_KEY", "vanhg dep trai vanhg dep trai vv"); 
-        let token = issue_token(AccountId(3)); 
-        let filter = auth();
+        let key_ring = test_key_ring();
+        let token = issue_token(AccountId(3), None, &tomorrow(), &key_ring); 
+        let filter = auth(Store::lazy(), key_ring);
         let res = warp::test::request()
         .header("Authorization", token)
         .filter(&filter); 
         assert_eq!(res.await.unwrap().account_id, AccountId(3)); 
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn session_token_has_every_scope() {
+        let key_ring = test_key_ring();
+        let token = issue_token(AccountId(3), None, &tomorrow(), &key_ring);
+        let filter = auth(Store::lazy(), key_ring);
+        let session = warp::test::request()
+            .header("Authorization", token)
+            .filter(&filter)
+            .await
+            .unwrap();
+        assert!(session.has_scope(Scope::WriteQuestions));
+        assert!(session.api_token_id.is_none());
+    }
+
+    #[tokio::test]
+    async fn bearer_scheme_is_accepted() {
+        let key_ring = test_key_ring();
+        let token = issue_token(AccountId(3), None, &tomorrow(), &key_ring);
+        let filter = auth(Store::lazy(), key_ring);
+        let session = warp::test::request()
+            .header("Authorization", format!("Bearer {}", token))
+            .filter(&filter)
+            .await
+            .unwrap();
+        assert_eq!(session.account_id, AccountId(3));
+    }
+
+    #[test]
+    fn bearer_token_parsing() {
+        assert_eq!(bearer_token("Bearer abc").unwrap(), "abc");
+        assert_eq!(bearer_token("bearer  abc ").unwrap(), "abc");
+        assert_eq!(bearer_token("abc").unwrap(), "abc");
+        assert!(bearer_token("Basic dXNlcjpwYXNz").is_err());
+        assert!(bearer_token("Bearer ").is_err());
+    }
+
+    #[tokio::test]
+    async fn missing_or_bad_tokens_get_401() {
+        let filter = auth(Store::lazy(), test_key_ring())
+            .map(|_| "ok")
+            .recover(handle_errors::return_error);
+
+        let res = warp::test::request().reply(&filter).await;
+        assert_eq!(res.status(), 401);
+        assert_eq!(
+            res.headers()["www-authenticate"],
+            "Bearer realm=\"rust_web_dev\""
+        );
+
+        let res = warp::test::request()
+            .header("Authorization", "Bearer v2.local.garbage")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 401);
+        assert_eq!(
+            res.headers()["www-authenticate"],
+            "Bearer realm=\"rust_web_dev\", error=\"invalid_token\""
+        );
+    }
+
+    #[tokio::test]
+    async fn optional_auth_without_token() {
+        let filter = optional_auth(Store::lazy(), test_key_ring());
+        let session = warp::test::request().filter(&filter).await.unwrap();
+        assert!(session.is_none());
+
+        let res = warp::test::request()
+            .header("Authorization", "Bearer v2.local.garbage")
+            .filter(&filter)
+            .await;
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn tokens_survive_key_rotation() {
+        let old_ring = key_ring("k0", b"an older key of exactly 32 bytes", Vec::new());
+        let token = issue_token(AccountId(3), None, &tomorrow(), &old_ring);
+
+        let rotated = key_ring(
+            "k1",
+            b"vanhg dep trai vanhg dep trai vv",
+            vec![PasetoKey::new("k0", b"an older key of exactly 32 bytes").unwrap()],
+        );
+        assert_eq!(
+            verify_token(token.clone(), &rotated).unwrap().account_id,
+            AccountId(3)
+        );
+
+        // Once the old key is dropped, its tokens are refused.
+        assert!(verify_token(token, &test_key_ring()).is_err());
+    }
+
+    #[test]
+    fn tokens_without_footer_use_current_key() {
+        let key = b"vanhg dep trai vanhg dep trai vv";
+        let token = paseto::tokens::PasetoBuilder::new()
+            .set_encryption_key(key)
+            .set_expiration(&(chrono::Utc::now() + chrono::Duration::days(1)))
+            .set_not_before(&chrono::Utc::now())
+            .set_claim("account_id", serde_json::json!(AccountId(5)))
+            .build()
+            .unwrap();
+        assert_eq!(
+            verify_token(token, &test_key_ring()).unwrap().account_id,
+            AccountId(5)
+        );
+    }
+
+    #[test]
+    fn public_tokens_are_verified_with_public_keys() {
+        let signing_key = SigningKey::from_seed("p1", &[7; 32]).unwrap();
+        let signer = KeyRing::new(
+            PasetoKey::new("k1", b"vanhg dep trai vanhg dep trai vv").unwrap(),
+            Vec::new(),
+            Some(signing_key.clone()),
+            Vec::new(),
+        )
+        .unwrap();
+        let token = issue_token(AccountId(4), None, &tomorrow(), &signer);
+        assert!(token.starts_with("v2.public."));
+        assert_eq!(
+            verify_token(token.clone(), &signer).unwrap().account_id,
+            AccountId(4)
+        );
+
+        // A service only knowing the public key can verify the token too.
+        let verifier = KeyRing::new(
+            PasetoKey::new("other", b"a different key of exactly 32 b.").unwrap(),
+            Vec::new(),
+            None,
+            vec![signing_key.public_key()],
+        )
+        .unwrap();
+        assert_eq!(
+            verify_token(token.clone(), &verifier).unwrap().account_id,
+            AccountId(4)
+        );
+
+        // Without the public key, the token is refused.
+        assert!(verify_token(token, &test_key_ring()).is_err());
+    }
+
+    #[test]
+    fn api_tokens_are_random_and_hashed() {
+        let first = generate_api_token();
+        let second = generate_api_token();
+        assert!(first.starts_with(API_TOKEN_PREFIX));
+        assert_ne!(first, second);
+        assert_eq!(hash_api_token(&first), hash_api_token(&first));
+        assert_ne!(hash_api_token(&first), hash_api_token(&second));
+        assert_eq!(hash_api_token(&first).len(), 64);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL, run with --ignored"]
+    async fn follow_location_of_registered_accounts() {
+        use super::{get_account, register};
+        use crate::password::PasswordHasher;
+
+        let store = Store::test_database().await;
+        let hasher = PasswordHasher {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+            variant: argon2::Variant::Argon2id,
+        };
+
+        let store_filter = warp::any().map({
+            let store = store.clone();
+            move || store.clone()
+        });
+        let post = warp::post()
+            .and(warp::path("registration"))
+            .and(store_filter.clone())
+            .and(warp::any().map(move || hasher.clone()))
+            .and(warp::body::json())
+            .and_then(register);
+        let get = warp::get()
+            .and(warp::path("accounts"))
+            .and(warp::path::param::<i32>())
+            .and(store_filter)
+            .and_then(get_account);
+        let routes = post.or(get).recover(handle_errors::return_error);
+
+        let email = format!("location-{}@test.invalid", uuid::Uuid::new_v4());
+        let response = warp::test::request()
+            .method("POST")
+            .path("/registration")
+            .json(&serde_json::json!({
+                "email": email,
+                "password": "secret",
+                "display_name": "Location"
+            }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 201);
+        let location = response.headers()["location"].to_str().unwrap().to_string();
+
+        let response = warp::test::request()
+            .path(&location)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        let account: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(account["display_name"], "Location");
+        // The profile is public, the email address stays private.
+        assert!(account.get("email").is_none());
+
+        sqlx::query("DELETE FROM accounts WHERE lower(email) = lower($1)")
+            .bind(email)
+            .execute(&store.connection)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,16 @@
+use handle_errors::CustomError;
+use tracing::instrument;
+
+use crate::store::Store;
+
+/// Show the hit and miss counters of the read cache from the
+/// `/cache/stats` route. Answers 404 when the cache is disabled.
+#[instrument]
+pub async fn get_cache_stats(
+    store: Store,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match store.cache_stats() {
+        Some(stats) => Ok(warp::reply::json(&stats)),
+        None => Err(warp::reject::custom(CustomError::NotFound)),
+    }
+}
@@ -0,0 +1,114 @@
+use serde::Serialize;
+use std::time::Duration;
+use tracing::instrument;
+use warp::http::StatusCode;
+
+use crate::server::Shutdown;
+use crate::store::Store;
+
+/// How long `/readyz` waits for the database, load balancers usually give
+/// up after a few seconds.
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Debug, PartialEq)]
+struct Readiness {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pending_migrations: Vec<i64>,
+}
+
+/// Version of this build, computed by `build.rs`.
+#[derive(Serialize, Debug)]
+struct BuildInfo {
+    version: &'static str,
+    commit: &'static str,
+    platform: &'static str,
+    build: &'static str,
+}
+
+/// Liveness of the process from the `/healthz` route, which answers as
+/// long as the server runs.
+pub async fn healthz() -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&Readiness {
+        status: "ok",
+        pending_migrations: Vec::new(),
+    }))
+}
+
+/// Readiness from the `/readyz` route: 200 when the databases answer and
+/// every migration is applied, 503 otherwise or while draining.
+#[instrument(skip(store))]
+pub async fn readyz(
+    shutdown: Shutdown,
+    store: Store,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let readiness = if shutdown.is_draining() {
+        Readiness {
+            status: "draining",
+            pending_migrations: Vec::new(),
+        }
+    } else {
+        match tokio::time::timeout(READY_TIMEOUT, store.pending_migrations()).await {
+            Ok(Ok(pending)) if pending.is_empty() => Readiness {
+                status: "ready",
+                pending_migrations: pending,
+            },
+            Ok(Ok(pending)) => Readiness {
+                status: "migrations pending",
+                pending_migrations: pending,
+            },
+            Ok(Err(_)) | Err(_) => Readiness {
+                status: "database unavailable",
+                pending_migrations: Vec::new(),
+            },
+        }
+    };
+
+    let status = match readiness.status {
+        "ready" => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&readiness), status))
+}
+
+/// The version, git commit and target platform of this build from the
+/// `/version` route.
+pub async fn version() -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("RUST_WEB_DEV_COMMIT"),
+        platform: env!("RUST_WEB_DEV_PLATFORM"),
+        build: env!("RUST_WEB_DEV_VERSION"),
+    }))
+}
+
+#[cfg(test)]
+mod health_tests {
+    use super::*;
+    use warp::Reply;
+
+    #[tokio::test]
+    async fn not_ready_while_draining() {
+        let shutdown = Shutdown::new();
+        shutdown.start_draining();
+        let response = readyz(shutdown, Store::lazy())
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], br#"{"status":"draining"}"#);
+    }
+
+    #[tokio::test]
+    async fn report_build() {
+        let response = version().await.unwrap().into_response();
+        let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let build: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(build["version"], env!("CARGO_PKG_VERSION"));
+        assert!(build["build"]
+            .as_str()
+            .unwrap()
+            .ends_with(build["platform"].as_str().unwrap()));
+    }
+}
@@ -0,0 +1,15 @@
+use warp::http::header::CONTENT_TYPE;
+
+use crate::metrics;
+use crate::store::Store;
+
+/// The request, database and domain metrics from the `/metrics` route,
+/// in the Prometheus text format.
+pub async fn get_metrics(store: Store) -> Result<impl warp::Reply, warp::Rejection> {
+    let text = metrics::render(&store)?;
+    Ok(warp::reply::with_header(
+        text,
+        CONTENT_TYPE,
+        prometheus::TEXT_FORMAT,
+    ))
+}
@@ -1,3 +1,10 @@
 pub mod answer;
 pub mod question;
-pub mod authentication;
\ No newline at end of file
+pub mod authentication;
+pub mod api_token;
+pub mod oidc;
+pub mod session;
+pub mod precondition;
+pub mod cache;
+pub mod health;
+pub mod metrics;
@@ -0,0 +1,89 @@
+use rand::Rng;
+use std::collections::HashMap;
+use tracing::{event, instrument, Level};
+use warp::http::Uri;
+
+use handle_errors::CustomError;
+
+use crate::controllers::authentication::start_session;
+use crate::key_ring::KeyRing;
+use crate::oidc::OidcClient;
+use crate::password::PasswordHasher;
+use crate::store::Store;
+use crate::types::account::ClientInfo;
+
+/// Start a single sign-on from the `/oidc/login` route
+/// # Example query
+/// GET requests to this route redirect the browser to the sign in page of
+/// the corporate identity provider.
+/// ```
+/// /oidc/login
+/// ```
+#[instrument]
+pub async fn login(oidc: OidcClient) -> Result<impl warp::Reply, warp::Rejection> {
+    let url = oidc.start_login()?;
+    let uri = url
+        .parse::<Uri>()
+        .map_err(|e| CustomError::OidcError(e.to_string()))?;
+
+    Ok(warp::redirect::temporary(uri))
+}
+
+/// Finish a single sign-on on the `/oidc/callback` route
+/// # Example query
+/// The identity provider redirects the browser to this route with the
+/// query params code and state:
+/// ```
+/// /oidc/callback?code=SplxlOBeZQQYbYS6WxSbIA&state=af0ifjsldkj
+/// ```
+/// The account with the verified email of the user is used, or created
+/// when it doesn't exist yet. The response is the token we use to
+/// authentication, as for `/login`.
+#[instrument(skip(params))]
+pub async fn callback(
+    params: HashMap<String, String>,
+    oidc: OidcClient,
+    store: Store,
+    hasher: PasswordHasher,
+    key_ring: KeyRing,
+    client: ClientInfo,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(error) = params.get("error") {
+        return Err(warp::reject::custom(CustomError::OidcError(format!(
+            "identity provider answered {}",
+            error
+        ))));
+    }
+
+    let (code, state) = match (params.get("code"), params.get("state")) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return Err(warp::reject::custom(CustomError::MissingParameters)),
+    };
+
+    let user = oidc.finish_login(state, code).await?;
+    let email = match user.email {
+        Some(email) if user.email_verified => email,
+        _ => {
+            return Err(warp::reject::custom(CustomError::OidcError(format!(
+                "no verified email for subject {}",
+                user.sub
+            ))))
+        }
+    };
+
+    // Accounts created here have a random password nobody knows, so they
+    // can only sign in through the identity provider.
+    let random_password = rand::thread_rng().gen::<[u8; 32]>().to_vec();
+    let password = hasher.hash(random_password).await?;
+    let account = store.clone().get_or_create_account(email, password).await?;
+    event!(Level::INFO, account_id = ?account.id, "signed in through OIDC");
+
+    let token = start_session(
+        store,
+        account.id.expect("id not found"),
+        client,
+        &key_ring,
+    )
+    .await?;
+    Ok(warp::reply::json(&token))
+}
@@ -0,0 +1,321 @@
+use chrono::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use warp::http::{
+    header::{CACHE_CONTROL, ETAG, LAST_MODIFIED, LOCATION, VARY},
+    HeaderValue, StatusCode,
+};
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+use handle_errors::CustomError;
+
+/// How long shared caches may serve public reads without revalidating.
+const PUBLIC_MAX_AGE_SECS: u32 = 30;
+
+/// Format of dates in HTTP headers, always in GMT.
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// The versions a client expects to edit, from its `If-Match` header.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IfMatch {
+    /// `If-Match: *`, any existing version may be overwritten.
+    Any,
+    /// The versions of the ETags listed in the header.
+    Versions(Vec<i32>),
+}
+
+impl IfMatch {
+    /// Parses an `If-Match` header. Only the version part of the ETags is
+    /// kept, so edits aren't refused because an answer was added. Weak and
+    /// malformed ETags are left out, so they never match.
+    pub fn parse(header: &str) -> IfMatch {
+        if header.trim() == "*" {
+            return IfMatch::Any;
+        }
+
+        IfMatch::Versions(
+            header
+                .split(',')
+                .map(str::trim)
+                .filter_map(|etag| etag.strip_prefix('"')?.strip_suffix('"'))
+                .filter_map(|etag| etag.split('-').next()?.parse::<i32>().ok())
+                .collect(),
+        )
+    }
+
+    /// The versions to check in the database, `None` when any will do.
+    pub fn versions(&self) -> Option<Vec<i32>> {
+        match self {
+            IfMatch::Any => None,
+            IfMatch::Versions(versions) => Some(versions.clone()),
+        }
+    }
+}
+
+/// The validators a client sent to revalidate a cached read.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Conditions {
+    /// ETags of the `If-None-Match` header.
+    if_none_match: Option<String>,
+    /// Date of the `If-Modified-Since` header.
+    if_modified_since: Option<DateTime<Utc>>,
+}
+
+impl Conditions {
+    /// Whether the client's copy is still current, so a 304 can be sent.
+    /// `If-Modified-Since` is only used without `If-None-Match`.
+    fn not_modified(&self, etag: &str, last_modified: Option<DateTime<Utc>>) -> bool {
+        match (&self.if_none_match, self.if_modified_since, last_modified) {
+            (Some(header), _, _) => header.split(',').map(str::trim).any(|tag| {
+                tag == "*" || weak_tag(tag) == weak_tag(etag)
+            }),
+            (None, Some(since), Some(modified)) => {
+                modified.timestamp() <= since.timestamp()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Strips the weak marker, `If-None-Match` uses the weak comparison.
+fn weak_tag(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+/// First 16 hex digits of the SHA-256 of a response body.
+fn body_hash(body: &[u8]) -> String {
+    hex::encode(&Sha256::digest(body)[..8])
+}
+
+/// The ETag of a question or answer: its version, followed by a hash of
+/// the body so the tag also changes with derived fields like
+/// `answer_count`.
+fn versioned_etag(version: i32, body: &[u8]) -> String {
+    format!("\"{}-{}\"", version, body_hash(body))
+}
+
+/// Replies with a question or answer as json and its `ETag`, e.g. after
+/// an edit.
+pub fn versioned_json<T: Serialize>(value: &T, version: i32) -> Response {
+    let body = serde_json::to_vec(value).expect("serializable reply");
+    let etag = versioned_etag(version, &body);
+    let mut response = json_response(body);
+    set_header(&mut response, ETAG, etag);
+    response
+}
+
+/// Replies 201 Created with the `Location` of the resource a POST made,
+/// e.g. `/questions/7`.
+pub fn created(reply: impl Reply, location: String) -> Response {
+    let mut response = reply.into_response();
+    *response.status_mut() = StatusCode::CREATED;
+    set_header(&mut response, LOCATION, location);
+    response
+}
+
+/// Replies to a read with validators and cache headers, or with 304 Not
+/// Modified when the client's copy is still current. `version` is set for
+/// a single resource, lists get a weak ETag. Lists have no
+/// `last_modified`: removing a row from a list doesn't move the newest
+/// date in it, so `If-Modified-Since` would keep answering 304.
+/// Personalised replies may only be cached by the client.
+pub fn cached_json<T: Serialize>(
+    value: &T,
+    version: Option<i32>,
+    last_modified: Option<DateTime<Utc>>,
+    personalised: bool,
+    conditions: &Conditions,
+) -> Response {
+    let body = serde_json::to_vec(value).expect("serializable reply");
+    let etag = match version {
+        Some(version) => versioned_etag(version, &body),
+        None => format!("W/\"{}\"", body_hash(&body)),
+    };
+
+    let mut response = if conditions.not_modified(&etag, last_modified) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        json_response(body)
+    };
+
+    set_header(&mut response, ETAG, etag);
+    if let Some(last_modified) = last_modified {
+        set_header(
+            &mut response,
+            LAST_MODIFIED,
+            last_modified.format(HTTP_DATE).to_string(),
+        );
+    }
+    let cache_control = if personalised {
+        "private, no-cache".to_string()
+    } else {
+        format!("public, max-age={}", PUBLIC_MAX_AGE_SECS)
+    };
+    set_header(&mut response, CACHE_CONTROL, cache_control);
+    // The reply depends on whether a token was sent.
+    set_header(&mut response, VARY, "authorization".to_string());
+    response
+}
+
+fn json_response(body: Vec<u8>) -> Response {
+    let mut response = Response::new(body.into());
+    set_header(
+        &mut response,
+        warp::http::header::CONTENT_TYPE,
+        "application/json".to_string(),
+    );
+    response
+}
+
+fn set_header(
+    response: &mut Response,
+    name: warp::http::header::HeaderName,
+    value: String,
+) {
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        response.headers_mut().insert(name, value);
+    }
+}
+
+/// Extracts the `If-None-Match` and `If-Modified-Since` headers. Invalid
+/// dates are ignored.
+pub fn conditions(
+) -> impl Filter<Extract = (Conditions,), Error = std::convert::Infallible> + Clone {
+    warp::header::optional::<String>("if-none-match")
+        .or(warp::any().map(|| None))
+        .unify()
+        .and(
+            warp::header::optional::<String>("if-modified-since")
+                .or(warp::any().map(|| None))
+                .unify(),
+        )
+        .map(|if_none_match, if_modified_since: Option<String>| Conditions {
+            if_none_match,
+            if_modified_since: if_modified_since.and_then(|date| {
+                NaiveDateTime::parse_from_str(&date, HTTP_DATE)
+                    .ok()
+                    .map(|date| Utc.from_utc_datetime(&date))
+            }),
+        })
+}
+
+/// Extracts the `If-Match` header, rejecting requests without it with
+/// `PreconditionRequired`.
+pub fn if_match(
+) -> impl Filter<Extract = (IfMatch,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("if-match").and_then(
+        |header: Option<String>| async move {
+            match header {
+                Some(header) => Ok(IfMatch::parse(&header)),
+                None => Err(warp::reject::custom(
+                    CustomError::PreconditionRequired,
+                )),
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod precondition_tests {
+    use super::*;
+
+    #[test]
+    fn parse_if_match() {
+        assert_eq!(IfMatch::parse("*"), IfMatch::Any);
+        assert_eq!(IfMatch::parse("\"3\""), IfMatch::Versions(vec![3]));
+        assert_eq!(
+            IfMatch::parse("\"2\", \"3\""),
+            IfMatch::Versions(vec![2, 3])
+        );
+        // Weak ETags never match an edit.
+        assert_eq!(IfMatch::parse("W/\"3\""), IfMatch::Versions(vec![]));
+        assert_eq!(IfMatch::parse("3"), IfMatch::Versions(vec![]));
+        assert_eq!(
+            IfMatch::parse("\"4-0123456789abcdef\""),
+            IfMatch::Versions(vec![4])
+        );
+    }
+
+    #[test]
+    fn reply_created() {
+        let response = created(versioned_json(&"q", 1), "/questions/7".to_string());
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()[LOCATION], "/questions/7");
+        assert!(response.headers()[ETAG].to_str().unwrap().starts_with("\"1-"));
+    }
+
+    #[test]
+    fn revalidate_reads() {
+        let modified = Utc.with_ymd_and_hms(2026, 10, 18, 18, 0, 0).unwrap();
+        let fresh = cached_json(&"q", Some(4), Some(modified), false, &Conditions::default());
+        assert_eq!(fresh.status(), StatusCode::OK);
+        let etag = fresh.headers()[ETAG].to_str().unwrap().to_string();
+        assert!(etag.starts_with("\"4-"));
+        assert_eq!(
+            fresh.headers()[LAST_MODIFIED],
+            "Sun, 18 Oct 2026 18:00:00 GMT"
+        );
+
+        let by_etag = Conditions {
+            if_none_match: Some(format!("W/{}", etag)),
+            if_modified_since: None,
+        };
+        let cached = cached_json(&"q", Some(4), Some(modified), false, &by_etag);
+        assert_eq!(cached.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(cached.headers()[CACHE_CONTROL], "public, max-age=30");
+
+        let changed = cached_json(&"q2", Some(4), Some(modified), true, &by_etag);
+        assert_eq!(changed.status(), StatusCode::OK);
+        assert_eq!(changed.headers()[CACHE_CONTROL], "private, no-cache");
+
+        let by_date = Conditions {
+            if_none_match: None,
+            if_modified_since: Some(modified),
+        };
+        let cached = cached_json(&"q", Some(4), Some(modified), false, &by_date);
+        assert_eq!(cached.status(), StatusCode::NOT_MODIFIED);
+        let later = cached_json(
+            &"q",
+            Some(4),
+            Some(modified + chrono::Duration::seconds(1)),
+            false,
+            &by_date,
+        );
+        assert_eq!(later.status(), StatusCode::OK);
+
+        // Lists are only revalidated by their ETag.
+        let list = cached_json(&["q"], None, None, false, &by_date);
+        assert_eq!(list.status(), StatusCode::OK);
+        assert!(!list.headers().contains_key(LAST_MODIFIED));
+        assert!(list.headers()[ETAG].to_str().unwrap().starts_with("W/\""));
+    }
+
+    #[tokio::test]
+    async fn parse_conditions() {
+        let found = warp::test::request()
+            .header("if-none-match", "W/\"abc\"")
+            .header("if-modified-since", "Sun, 18 Oct 2026 18:00:00 GMT")
+            .filter(&conditions())
+            .await
+            .unwrap();
+        assert_eq!(found.if_none_match.as_deref(), Some("W/\"abc\""));
+        assert_eq!(
+            found.if_modified_since,
+            Some(Utc.with_ymd_and_hms(2026, 10, 18, 18, 0, 0).unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn require_if_match() {
+        let filter = if_match();
+        assert!(warp::test::request().filter(&filter).await.is_err());
+
+        let found = warp::test::request()
+            .header("if-match", "\"7\"")
+            .filter(&filter)
+            .await
+            .unwrap();
+        assert_eq!(found.versions(), Some(vec![7]));
+    }
+}
@@ -5,15 +5,19 @@ use tracing::{instrument};
 
 use crate::types::pagination::Pagination;
 use tracing::{event, Level};
-use crate::types::question::NewQuestion;
+use crate::types::question::{NewQuestion, QuestionPatch};
 use crate::types::account::Session;
+use crate::types::api_token::Scope;
+use crate::controllers::authentication::require_scope;
+use crate::controllers::precondition::{
+    cached_json, created, versioned_json, Conditions, IfMatch,
+};
+use handle_errors::CustomError;
 
 use crate::{
+    metrics,
     store::Store,
-    types::{
-        pagination::extract_pagination,
-        question::{Question},
-    },
+    types::pagination::extract_pagination,
 };
 
 /// This function gets a list of all the questions from '/questions' route
@@ -23,10 +27,15 @@ use crate::{
 /// ```
 /// /answers?limit=10&offset=0&question_id=1
 /// ```
+/// With a token in headers, each question also tells whether you asked it.
+/// The reply carries a weak `ETag`, and is answered with 304 when the
+/// client's copy is still current.
 
 #[instrument]
 pub async fn get_questions(
     params: HashMap<String, String>,
+    session: Option<Session>,
+    conditions: Conditions,
     store: Store,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     event!(target: "web", Level::INFO, "querying questions");
@@ -36,9 +45,11 @@ pub async fn get_questions(
         event!(Level::INFO, pagination = true);
         pagination = extract_pagination(params)?;
     }
-    match store.get_questions(pagination.limit, pagination.offset).await
+    let viewer = session.map(|session| session.account_id);
+    let personalised = viewer.is_some();
+    match store.get_questions(pagination.limit, pagination.offset, viewer).await
     {
-        Ok(res) => Ok(warp::reply::json(&res)),
+        Ok(res) => Ok(cached_json(&res, None, None, personalised, &conditions)),
         Err(e) => Err(warp::reject::custom(e)),
     }
 }
@@ -60,6 +71,7 @@ pub async fn add_question(
     store: Store,
     new_question: NewQuestion,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    require_scope(&session, Scope::WriteQuestions)?;
     let account_id = session.account_id;
 
     let question = NewQuestion {
@@ -69,39 +81,77 @@ pub async fn add_question(
     };
 
     match store.add_question(question, account_id).await {
-        Ok(question) => Ok(warp::reply::json(&question)),
+        Ok(question) => {
+            metrics::question_created();
+            Ok(created(
+                versioned_json(&question, question.version),
+                format!("/questions/{}", question.id.0),
+            ))
+        }
         Err(e) => Err(warp::reject::custom(e)),
     }
 }
 
+/// Get a single question from the `/questions/question_id` route
+/// # Example query
+/// GET requests to this route, with the query is
+/// the id of the question:
+///```
+/// /questions/2
+///```
+/// The `ETag` header of the response holds the version of the question,
+/// which must be sent back in `If-Match` to edit it. Like the list, the
+/// reply can be revalidated.
+#[instrument]
+pub async fn get_question(
+    id: i32,
+    session: Option<Session>,
+    conditions: Conditions,
+    store: Store,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let viewer = session.map(|session| session.account_id);
+    let personalised = viewer.is_some();
+    match store.get_question(id, viewer).await? {
+        Some(question) => Ok(cached_json(
+            &question,
+            Some(question.version),
+            Some(question.last_modified()),
+            personalised,
+            &conditions,
+        )),
+        None => Err(warp::reject::custom(CustomError::NotFound)),
+    }
+}
+
 /// Update an existing question from `/questions` route
 /// # Example query
-/// PUT requests to this route, with the body format is
-/// json with the id of the question and any of 3 key-value
-/// we want to update:
+/// PUT requests to this route, with the `If-Match` header set to the
+/// `ETag` of the question and the body format is
+/// json with the new title, content and tags of the question:
 ///```
 /// {
-///     "id":   "3",  
-///     "title": "Tai vi sao"
+///     "title": "Tai vi sao",
+///     "content": "Yeahh, cam xuc kia quay ve",
+///     "tags": ["messi"]
 /// }
 ///```
 #[instrument]
 pub async fn update_question(
     id: i32,
     session: Session,
+    if_match: IfMatch,
     store: Store,
-    question: Question,
+    question: NewQuestion,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    require_scope(&session, Scope::WriteQuestions)?;
     let account_id = session.account_id;
     if store.is_question_owner(id, &account_id).await? {
-        let question = Question {
-            id: question.id,
-            title: question.title,
-            content: question.content,
-            tags: question.tags,
-        };
-        match store.update_question(question, id, account_id).await {
-            Ok(res) => Ok(warp::reply::json(&res)),
+        match store
+            .update_question(question, id, account_id, if_match.versions())
+            .await
+        {
+            Ok(Some(res)) => Ok(versioned_json(&res, res.version)),
+            Ok(None) => Err(warp::reject::custom(CustomError::PreconditionFailed)),
             Err(e) => Err(warp::reject::custom(e)),
         }
         
@@ -110,6 +160,39 @@ pub async fn update_question(
     }
 }
 
+/// Partially update a question from `/questions/question_id` route
+/// # Example query
+/// PATCH requests to this route, with the `If-Match` header set to the
+/// `ETag` of the question and a json body with only the fields to change:
+///```
+/// {
+///     "title": "Tai vi sao"
+/// }
+///```
+#[instrument]
+pub async fn patch_question(
+    id: i32,
+    session: Session,
+    if_match: IfMatch,
+    store: Store,
+    patch: QuestionPatch,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    require_scope(&session, Scope::WriteQuestions)?;
+    let account_id = session.account_id;
+    if store.is_question_owner(id, &account_id).await? {
+        match store
+            .patch_question(patch, id, account_id, if_match.versions())
+            .await
+        {
+            Ok(Some(res)) => Ok(versioned_json(&res, res.version)),
+            Ok(None) => Err(warp::reject::custom(CustomError::PreconditionFailed)),
+            Err(e) => Err(warp::reject::custom(e)),
+        }
+    } else {
+        Err(warp::reject::custom(handle_errors::CustomError::Unauthorized))
+    }
+}
+
 
 /// Delete an existing question from `/questions/question_id` route
 /// # Example query
@@ -124,6 +207,7 @@ pub async fn delete_question(
     session: Session,
     store: Store,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    require_scope(&session, Scope::WriteQuestions)?;
     let account_id = session.account_id;
     if store.is_question_owner(id, &account_id).await? {
         match store.delete_question(id, account_id).await {
@@ -137,3 +221,101 @@ pub async fn delete_question(
         Err(warp::reject::custom(handle_errors::CustomError::Unauthorized))
     }
 }
+
+#[cfg(test)]
+mod question_tests {
+    use super::*;
+    use crate::controllers::precondition::conditions;
+    use crate::types::account::Account;
+    use chrono::{Duration, Utc};
+    use warp::Filter;
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL, run with --ignored"]
+    async fn revalidate_lists_after_a_delete() {
+        let store = Store::test_database().await;
+
+        let email = format!("revalidate-{}@test.invalid", uuid::Uuid::new_v4());
+        let account = store
+            .clone()
+            .add_account(Account {
+                id: None,
+                email: email.clone(),
+                password: "!".to_string(),
+                display_name: None,
+            })
+            .await
+            .unwrap();
+        let mut ids = Vec::new();
+        for title in ["kept", "deleted"] {
+            let question = store
+                .clone()
+                .add_question(
+                    NewQuestion {
+                        title: title.to_string(),
+                        content: title.to_string(),
+                        tags: None,
+                    },
+                    account.id.clone(),
+                )
+                .await
+                .unwrap();
+            ids.push(question.id.0);
+        }
+
+        let routes = warp::get()
+            .and(warp::query())
+            .and(warp::any().map(|| None))
+            .and(conditions())
+            .and(warp::any().map({
+                let store = store.clone();
+                move || store.clone()
+            }))
+            .and_then(get_questions);
+
+        let response = warp::test::request().path("/").reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        assert!(!response.headers().contains_key("last-modified"));
+        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+
+        store
+            .clone()
+            .delete_question(ids[1], account.id.clone())
+            .await
+            .unwrap();
+
+        // No row in the list changed its date, only the ETag tells the
+        // list changed.
+        let since = (Utc::now() + Duration::hours(1))
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        let response = warp::test::request()
+            .path("/")
+            .header("if-none-match", etag)
+            .header("if-modified-since", since.clone())
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        let listed: Vec<serde_json::Value> = serde_json::from_slice(response.body()).unwrap();
+        assert!(listed.iter().any(|question| question["id"] == ids[0]));
+        assert!(!listed.iter().any(|question| question["id"] == ids[1]));
+
+        let response = warp::test::request()
+            .path("/")
+            .header("if-modified-since", since)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        sqlx::query("DELETE FROM questions WHERE id = $1")
+            .bind(ids[0])
+            .execute(&store.connection)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM accounts WHERE id = $1")
+            .bind(account.id.0)
+            .execute(&store.connection)
+            .await
+            .unwrap();
+    }
+}
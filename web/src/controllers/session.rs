@@ -0,0 +1,70 @@
+use tracing::instrument;
+use warp::http::StatusCode;
+
+use crate::controllers::authentication::require_scope;
+use crate::store::Store;
+use crate::types::account::{Session, SessionId};
+use crate::types::api_token::Scope;
+
+/// List the active login sessions from the `/me/sessions` route
+/// # Example response
+/// ```
+/// [{
+///     "id": "0b5d6a0e-5f7c-4c1e-9a51-3f3f8c1f52c8",
+///     "created_at": "2024-02-20T08:00:00Z",
+///     "last_seen_at": "2024-02-20T09:30:00Z",
+///     "expires_at": "2024-02-21T08:00:00Z",
+///     "user_agent": "curl/8.5.0",
+///     "ip": "127.0.0.1",
+///     "current": true
+/// }]
+/// ```
+#[instrument]
+pub async fn get_sessions(
+    session: Session,
+    store: Store,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    require_scope(&session, Scope::Read)?;
+
+    match store.get_sessions(session.account_id).await {
+        Ok(mut sessions) => {
+            for active in sessions.iter_mut() {
+                active.current = Some(active.id) == session.session_id;
+            }
+            Ok(warp::reply::json(&sessions))
+        }
+        Err(e) => Err(warp::reject::custom(e)),
+    }
+}
+
+/// Sign out a login session from the `/me/sessions/session_id` route
+/// # Example query
+/// DELETE requests to this route, with the query is
+/// the id of the session we want to sign out
+///```
+/// /me/sessions/0b5d6a0e-5f7c-4c1e-9a51-3f3f8c1f52c8
+///```
+#[instrument]
+pub async fn revoke_session(
+    id: uuid::Uuid,
+    session: Session,
+    store: Store,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Personal access tokens can't sign users out.
+    if session.api_token_id.is_some() {
+        return Err(warp::reject::custom(
+            handle_errors::CustomError::InsufficientScope,
+        ));
+    }
+
+    match store.revoke_session(SessionId(id), session.account_id).await {
+        Ok(true) => Ok(warp::reply::with_status(
+            format!("Session {} signed out", id),
+            StatusCode::OK,
+        )),
+        Ok(false) => Err(warp::reject::custom(
+            handle_errors::CustomError::Unauthorized,
+        )),
+        Err(e) => Err(warp::reject::custom(e)),
+    }
+}
@@ -0,0 +1,489 @@
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::Arc;
+
+use handle_errors::CustomError;
+
+/// Length in bytes every v2.local PASETO key must have.
+pub const PASETO_KEY_LEN: usize = 32;
+
+/// Length in bytes of Ed25519 seeds and public keys.
+pub const ED25519_KEY_LEN: usize = 32;
+
+/// Key id used when `PASETO_KEY_ID` is not set.
+const DEFAULT_KEY_ID: &str = "default";
+
+/// Key id used when `PASETO_SIGNING_KEY_ID` is not set.
+const DEFAULT_SIGNING_KEY_ID: &str = "default-public";
+
+/// A symmetric PASETO key together with the id placed in token footers.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PasetoKey {
+    /// Identifier of the key, written to the footer of issued tokens.
+    pub id: String,
+    key: [u8; PASETO_KEY_LEN],
+}
+
+impl PasetoKey {
+    /// Creates a key, refusing anything which is not exactly 32 bytes.
+    pub fn new(id: &str, key: &[u8]) -> Result<PasetoKey, CustomError> {
+        if id.is_empty() {
+            return Err(CustomError::ConfigError(
+                "PASETO key id must not be empty".to_string(),
+            ));
+        }
+
+        let key: [u8; PASETO_KEY_LEN] = key.try_into().map_err(|_| {
+            CustomError::ConfigError(format!(
+                "PASETO key '{}' must be exactly {} bytes, got {}",
+                id,
+                PASETO_KEY_LEN,
+                key.len()
+            ))
+        })?;
+
+        Ok(PasetoKey {
+            id: id.to_string(),
+            key,
+        })
+    }
+
+    /// The raw key material.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+impl std::fmt::Debug for PasetoKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PasetoKey")
+            .field("id", &self.id)
+            .field("key", &"<redacted>")
+            .finish()
+    }
+}
+
+/// An Ed25519 key pair used to sign v2.public tokens.
+#[derive(Clone)]
+pub struct SigningKey {
+    /// Identifier of the key, written to the footer of issued tokens.
+    pub id: String,
+    key_pair: Arc<Ed25519KeyPair>,
+}
+
+impl SigningKey {
+    /// Creates a signing key from a 32 bytes Ed25519 seed.
+    pub fn from_seed(id: &str, seed: &[u8]) -> Result<SigningKey, CustomError> {
+        if id.is_empty() || seed.len() != ED25519_KEY_LEN {
+            return Err(CustomError::ConfigError(format!(
+                "Ed25519 signing key '{}' needs an id and a {} bytes seed",
+                id, ED25519_KEY_LEN
+            )));
+        }
+
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(seed).map_err(|e| {
+            CustomError::ConfigError(format!(
+                "Invalid Ed25519 signing key '{}': {}",
+                id, e
+            ))
+        })?;
+
+        Ok(SigningKey {
+            id: id.to_string(),
+            key_pair: Arc::new(key_pair),
+        })
+    }
+
+    /// The key pair used by the PASETO builder.
+    pub fn key_pair(&self) -> &Ed25519KeyPair {
+        &self.key_pair
+    }
+
+    /// The public half of the key, which other services verify with.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey {
+            id: self.id.clone(),
+            key: self.key_pair.public_key().as_ref().to_vec(),
+        }
+    }
+}
+
+impl PartialEq for SigningKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.public_key() == other.public_key()
+    }
+}
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("id", &self.id)
+            .field("key_pair", &"<redacted>")
+            .finish()
+    }
+}
+
+/// An Ed25519 public key able to verify v2.public tokens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicKey {
+    /// Identifier of the key, found in the footer of tokens.
+    pub id: String,
+    key: Vec<u8>,
+}
+
+impl PublicKey {
+    /// Creates a public key, refusing anything which is not 32 bytes.
+    pub fn new(id: &str, key: &[u8]) -> Result<PublicKey, CustomError> {
+        if id.is_empty() || key.len() != ED25519_KEY_LEN {
+            return Err(CustomError::ConfigError(format!(
+                "Ed25519 public key '{}' needs an id and {} bytes",
+                id, ED25519_KEY_LEN
+            )));
+        }
+
+        Ok(PublicKey {
+            id: id.to_string(),
+            key: key.to_vec(),
+        })
+    }
+
+    /// The raw key material.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// The representation published at `/.well-known/paseto-keys`.
+    pub fn to_published(&self) -> PublishedKey {
+        PublishedKey {
+            kid: self.id.clone(),
+            kty: "OKP",
+            crv: "Ed25519",
+            alg: "v2.public",
+            x: base64::encode_config(&self.key, base64::URL_SAFE_NO_PAD),
+        }
+    }
+}
+
+/// A public key as published to other services, following the JWK
+/// layout of Ed25519 keys.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PublishedKey {
+    /// Id of the key, matching the `kid` in token footers.
+    pub kid: String,
+    /// Key type, always `OKP`.
+    pub kty: &'static str,
+    /// Curve, always `Ed25519`.
+    pub crv: &'static str,
+    /// The PASETO version and purpose the key verifies.
+    pub alg: &'static str,
+    /// The public key, base64url encoded without padding.
+    pub x: String,
+}
+
+/// The document served at `/.well-known/paseto-keys`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PublishedKeySet {
+    /// Every public key currently accepted.
+    pub keys: Vec<PublishedKey>,
+}
+
+/// Which kind of PASETO token the server issues.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Symmetrically encrypted v2.local tokens, only this server can read.
+    Local,
+    /// Ed25519 signed v2.public tokens, any service with the public key
+    /// can verify.
+    Public,
+}
+
+/// The footer of issued tokens, naming the key used to encrypt them.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct TokenFooter {
+    /// Id of the key used for the token.
+    pub kid: String,
+}
+
+/// The set of PASETO keys known to the server. Tokens are always issued
+/// with the current key, while older keys stay valid for verification
+/// during a rotation window.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyRing {
+    current: PasetoKey,
+    previous: Vec<PasetoKey>,
+    signing_key: Option<SigningKey>,
+    public_keys: Vec<PublicKey>,
+    token_kind: TokenKind,
+}
+
+impl KeyRing {
+    /// Creates a key ring, refusing duplicated key ids. When a signing key
+    /// is given, new tokens are v2.public tokens, otherwise v2.local ones.
+    pub fn new(
+        current: PasetoKey,
+        previous: Vec<PasetoKey>,
+        signing_key: Option<SigningKey>,
+        public_keys: Vec<PublicKey>,
+    ) -> Result<KeyRing, CustomError> {
+        let mut ids = vec![&current.id];
+        let other_ids = previous
+            .iter()
+            .map(|key| &key.id)
+            .chain(signing_key.iter().map(|key| &key.id))
+            .chain(public_keys.iter().map(|key| &key.id));
+        for id in other_ids {
+            if ids.contains(&id) {
+                return Err(CustomError::ConfigError(format!(
+                    "PASETO key id '{}' is used twice",
+                    id
+                )));
+            }
+            ids.push(id);
+        }
+
+        let token_kind = match signing_key {
+            Some(_) => TokenKind::Public,
+            None => TokenKind::Local,
+        };
+
+        Ok(KeyRing {
+            current,
+            previous,
+            signing_key,
+            public_keys,
+            token_kind,
+        })
+    }
+
+    /// Loads the key ring from the environment:
+    /// - `PASETO_KEY`: the key used to issue new tokens,
+    /// - `PASETO_KEY_ID`: its id, `default` when unset,
+    /// - `PASETO_VERIFY_KEYS`: older keys still accepted, written as
+    ///   `id:key` pairs separated by commas,
+    /// - `PASETO_TOKEN_KIND`: `local` (default) or `public`,
+    /// - `PASETO_SIGNING_KEY`: base64 Ed25519 seed signing v2.public tokens,
+    /// - `PASETO_SIGNING_KEY_ID`: its id, `default-public` when unset,
+    /// - `PASETO_VERIFY_PUBLIC_KEYS`: older base64 Ed25519 public keys still
+    ///   accepted, written as `id:key` pairs separated by commas.
+    pub fn from_env() -> Result<KeyRing, CustomError> {
+        let key = env::var("PASETO_KEY").map_err(|_| {
+            CustomError::ConfigError("PASETO_KEY not set".to_string())
+        })?;
+        let key_id = env::var("PASETO_KEY_ID")
+            .unwrap_or_else(|_| DEFAULT_KEY_ID.to_string());
+        let previous = match env::var("PASETO_VERIFY_KEYS") {
+            Ok(keys) => parse_verify_keys(&keys)?,
+            Err(_) => Vec::new(),
+        };
+
+        let token_kind = match env::var("PASETO_TOKEN_KIND").as_deref() {
+            Ok("local") | Err(_) => TokenKind::Local,
+            Ok("public") => TokenKind::Public,
+            Ok(other) => {
+                return Err(CustomError::ConfigError(format!(
+                    "PASETO_TOKEN_KIND must be local or public, got {}",
+                    other
+                )))
+            }
+        };
+        let signing_key = match token_kind {
+            TokenKind::Local => None,
+            TokenKind::Public => {
+                let seed = env::var("PASETO_SIGNING_KEY").map_err(|_| {
+                    CustomError::ConfigError(
+                        "PASETO_SIGNING_KEY not set for public tokens"
+                            .to_string(),
+                    )
+                })?;
+                let id = env::var("PASETO_SIGNING_KEY_ID")
+                    .unwrap_or_else(|_| DEFAULT_SIGNING_KEY_ID.to_string());
+                Some(SigningKey::from_seed(&id, &decode_base64(&id, &seed)?)?)
+            }
+        };
+        let public_keys = match env::var("PASETO_VERIFY_PUBLIC_KEYS") {
+            Ok(keys) => parse_verify_public_keys(&keys)?,
+            Err(_) => Vec::new(),
+        };
+
+        KeyRing::new(
+            PasetoKey::new(&key_id, key.as_bytes())?,
+            previous,
+            signing_key,
+            public_keys,
+        )
+    }
+
+    /// The kind of token the server issues.
+    pub fn token_kind(&self) -> TokenKind {
+        self.token_kind
+    }
+
+    /// The key new v2.local tokens are issued with.
+    pub fn signing_key(&self) -> &PasetoKey {
+        &self.current
+    }
+
+    /// The key new v2.public tokens are signed with, if any.
+    pub fn public_signing_key(&self) -> Option<&SigningKey> {
+        self.signing_key.as_ref()
+    }
+
+    /// Finds a key by the id found in a token footer.
+    pub fn find(&self, id: &str) -> Option<&PasetoKey> {
+        std::iter::once(&self.current)
+            .chain(self.previous.iter())
+            .find(|key| key.id == id)
+    }
+
+    /// Every public key able to verify v2.public tokens, starting with the
+    /// one of the current signing key.
+    pub fn public_keys(&self) -> Vec<PublicKey> {
+        self.signing_key
+            .iter()
+            .map(SigningKey::public_key)
+            .chain(self.public_keys.iter().cloned())
+            .collect()
+    }
+
+    /// Finds a public key by the id found in a token footer.
+    pub fn find_public(&self, id: &str) -> Option<PublicKey> {
+        self.public_keys().into_iter().find(|key| key.id == id)
+    }
+}
+
+/// Parses `id:key` pairs separated by commas.
+fn parse_verify_keys(keys: &str) -> Result<Vec<PasetoKey>, CustomError> {
+    keys.split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| match pair.split_once(':') {
+            Some((id, key)) => PasetoKey::new(id.trim(), key.as_bytes()),
+            None => Err(CustomError::ConfigError(
+                "PASETO_VERIFY_KEYS entries must look like id:key".to_string(),
+            )),
+        })
+        .collect()
+}
+
+/// Parses `id:key` pairs of base64 Ed25519 public keys separated by commas.
+fn parse_verify_public_keys(keys: &str) -> Result<Vec<PublicKey>, CustomError> {
+    keys.split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| match pair.split_once(':') {
+            Some((id, key)) => {
+                let id = id.trim();
+                PublicKey::new(id, &decode_base64(id, key.trim())?)
+            }
+            None => Err(CustomError::ConfigError(
+                "PASETO_VERIFY_PUBLIC_KEYS entries must look like id:key"
+                    .to_string(),
+            )),
+        })
+        .collect()
+}
+
+/// Decodes base64 key material from the configuration.
+fn decode_base64(id: &str, value: &str) -> Result<Vec<u8>, CustomError> {
+    base64::decode(value.trim()).map_err(|e| {
+        CustomError::ConfigError(format!("Key '{}' is not valid base64: {}", id, e))
+    })
+}
+
+/// Builds the footer placed in tokens issued with the key of the given id.
+pub fn footer_for(kid: &str) -> String {
+    serde_json::to_string(&TokenFooter {
+        kid: kid.to_string(),
+    })
+    .expect("Failed to serialize token footer")
+}
+
+/// Reads the raw footer of a token, if it has one.
+pub fn raw_footer(token: &str) -> Option<String> {
+    let footer = token.splitn(4, '.').nth(3)?;
+    let footer = base64::decode_config(footer, base64::URL_SAFE_NO_PAD).ok()?;
+    String::from_utf8(footer).ok()
+}
+
+#[cfg(test)]
+mod key_ring_tests {
+    use super::*;
+
+    const KEY: &[u8] = b"vanhg dep trai vanhg dep trai vv";
+    const OLD_KEY: &[u8] = b"an older key of exactly 32 bytes";
+
+    #[test]
+    fn refuses_short_keys() {
+        assert!(PasetoKey::new("k1", b"too short").is_err());
+        assert!(PasetoKey::new("k1", KEY).is_ok());
+    }
+
+    #[test]
+    fn refuses_duplicated_ids() {
+        let current = PasetoKey::new("k1", KEY).unwrap();
+        let previous = vec![PasetoKey::new("k1", OLD_KEY).unwrap()];
+        assert!(KeyRing::new(current, previous, None, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn parses_verification_keys() {
+        assert!(parse_verify_keys("no separator here").is_err());
+
+        let keys = parse_verify_keys(
+            "k0:an older key of exactly 32 bytes,k-1:vanhg dep trai vanhg dep trai vv",
+        )
+        .unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].id, "k0");
+        assert_eq!(keys[1].as_bytes(), KEY);
+    }
+
+    #[test]
+    fn finds_current_and_previous_keys() {
+        let ring = KeyRing::new(
+            PasetoKey::new("k1", KEY).unwrap(),
+            vec![PasetoKey::new("k0", OLD_KEY).unwrap()],
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(ring.signing_key().id, "k1");
+        assert_eq!(ring.find("k0").unwrap().as_bytes(), OLD_KEY);
+        assert!(ring.find("k2").is_none());
+    }
+
+    #[test]
+    fn signing_key_makes_public_tokens() {
+        let signing_key = SigningKey::from_seed("p1", &[7; 32]).unwrap();
+        let old = PublicKey::new("p0", &[9; 32]).unwrap();
+        let ring = KeyRing::new(
+            PasetoKey::new("k1", KEY).unwrap(),
+            Vec::new(),
+            Some(signing_key.clone()),
+            vec![old.clone()],
+        )
+        .unwrap();
+        assert_eq!(ring.token_kind(), TokenKind::Public);
+        assert_eq!(ring.public_keys(), vec![signing_key.public_key(), old]);
+        assert!(ring.find_public("p0").is_some());
+        assert!(ring.find_public("k1").is_none());
+    }
+
+    #[test]
+    fn refuses_bad_public_keys() {
+        assert!(SigningKey::from_seed("p1", &[7; 16]).is_err());
+        assert!(PublicKey::new("p1", &[7; 31]).is_err());
+        assert!(parse_verify_public_keys("p0:not base64!").is_err());
+        let keys = parse_verify_public_keys(&format!(
+            "p0:{}",
+            base64::encode([9; 32])
+        ))
+        .unwrap();
+        assert_eq!(keys[0].as_bytes(), &[9; 32]);
+    }
+
+    #[test]
+    fn debug_output_hides_keys() {
+        let key = PasetoKey::new("k1", KEY).unwrap();
+        assert!(!format!("{:?}", key).contains("vanhg"));
+    }
+}
@@ -0,0 +1,285 @@
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::trace::TracerProvider;
+use serde::Serialize;
+use std::path::Path;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
+
+use handle_errors::CustomError;
+
+use crate::telemetry::TraceConfig;
+
+/// Modules logged at `level` unless `modules` says otherwise.
+const OWN_MODULES: [&str; 3] = ["handle_errors", "web", "warp"];
+
+/// Directive of the access log lines written by `request::finish`.
+const ACCESS_LOG: &str = "web::access=info";
+
+/// Format of the log lines.
+#[derive(clap::ArgEnum, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One json object per line, for log collectors.
+    Json,
+}
+
+/// How often the log file starts over.
+#[derive(clap::ArgEnum, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    Daily,
+    Hourly,
+    Minutely,
+}
+
+/// Logging settings, the `[log]` section of the configuration file.
+#[derive(clap::Args, Serialize, Clone, Debug, PartialEq)]
+pub struct LogConfig {
+    /// Which errors we want to log (info, warn or error)
+    #[clap(
+        name = "log-level",
+        short = 'l',
+        long = "log-level",
+        env = "LOG_LEVEL",
+        default_value = "warn"
+    )]
+    pub level: String,
+    /// Levels of other modules, e.g. `sqlx=warn,warp=info`
+    #[clap(name = "log-modules", long = "log-modules", env = "LOG_MODULES")]
+    pub modules: Option<String>,
+    /// Format of the log lines
+    #[clap(
+        name = "log-format",
+        long = "log-format",
+        env = "LOG_FORMAT",
+        arg_enum,
+        default_value = "text"
+    )]
+    pub format: LogFormat,
+    /// File the logs are also written to, e.g. `logs/web.log`
+    #[clap(name = "log-file", long = "log-file", env = "LOG_FILE")]
+    pub file: Option<String>,
+    /// How often the log file is rotated
+    #[clap(
+        name = "log-rotation",
+        long = "log-rotation",
+        env = "LOG_ROTATION",
+        arg_enum,
+        default_value = "daily"
+    )]
+    pub rotation: LogRotation,
+    /// Write one `web::access` line per request at info level, whatever
+    /// the level
+    #[clap(
+        name = "log-access",
+        long = "log-access",
+        env = "LOG_ACCESS",
+        default_value = "true",
+        parse(try_from_str)
+    )]
+    pub access: bool,
+}
+
+impl LogConfig {
+    /// The filter directives: `level` for our own modules, the access log,
+    /// then the levels of `modules`, which take precedence.
+    fn directives(&self, level: &str) -> String {
+        let modules: Vec<&str> = self
+            .modules
+            .iter()
+            .flat_map(|modules| modules.split(','))
+            .map(str::trim)
+            .filter(|directive| !directive.is_empty())
+            .collect();
+        let overridden = |module: &str| {
+            modules
+                .iter()
+                .any(|directive| directive.split('=').next() == Some(module))
+        };
+
+        OWN_MODULES
+            .iter()
+            .filter(|module| !overridden(module))
+            .map(|module| format!("{}={}", module, level))
+            .chain(self.access.then(|| ACCESS_LOG.to_string()))
+            .chain(modules.iter().map(|directive| directive.to_string()))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Checks the level and the module directives can be parsed.
+    pub fn filter(&self) -> Result<EnvFilter, CustomError> {
+        self.filter_at(&self.level)
+    }
+
+    /// The filter of the spans exported with `trace`, at its own level.
+    pub fn trace_filter(&self, trace: &TraceConfig) -> Result<EnvFilter, CustomError> {
+        self.filter_at(&trace.level)
+    }
+
+    fn filter_at(&self, level: &str) -> Result<EnvFilter, CustomError> {
+        EnvFilter::try_new(self.directives(level)).map_err(|e| {
+            CustomError::ConfigError(format!("invalid log level: {}", e))
+        })
+    }
+
+    /// Installs the global subscriber writing to stdout, to `file` when
+    /// set, and exporting spans as `trace` says. The returned guard
+    /// flushes the file and the spans when dropped, so it must live
+    /// until the server stops.
+    pub fn init(&self, trace: &TraceConfig) -> Result<LogGuard, CustomError> {
+        let (file_writer, guard) = match &self.file {
+            Some(file) => {
+                let path = Path::new(file);
+                let directory = match path.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => parent,
+                    _ => Path::new("."),
+                };
+                let prefix = path.file_name().ok_or_else(|| {
+                    CustomError::ConfigError(format!("invalid log file {:?}", file))
+                })?;
+                let appender = RollingFileAppender::new(
+                    self.rotation.into(),
+                    directory,
+                    prefix,
+                );
+                let (writer, guard) = tracing_appender::non_blocking(appender);
+                (Some(writer), Some(guard))
+            }
+            None => (None, None),
+        };
+
+        // Spans are exported at the trace level whatever the log level.
+        let tracer_provider = trace.tracer_provider()?;
+        let exported = match &tracer_provider {
+            Some(provider) => Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(provider.tracer("web"))
+                    .with_filter(self.trace_filter(trace)?),
+            ),
+            None => None,
+        };
+        let file_filter = self.filter()?;
+
+        // Record an event when each span closes.
+        // This can be used to time our routes' durations!
+        let registry = tracing_subscriber::registry().with(exported);
+        let installed = match self.format {
+            LogFormat::Text => registry
+                .with(
+                    tracing_subscriber::fmt::layer()
+                        .with_span_events(FmtSpan::CLOSE)
+                        .with_filter(self.filter()?),
+                )
+                .with(file_writer.map(|writer| {
+                    tracing_subscriber::fmt::layer()
+                        .with_span_events(FmtSpan::CLOSE)
+                        .with_ansi(false)
+                        .with_writer(writer)
+                        .with_filter(file_filter)
+                }))
+                .try_init(),
+            LogFormat::Json => registry
+                .with(
+                    tracing_subscriber::fmt::layer()
+                        .json()
+                        .with_span_events(FmtSpan::CLOSE)
+                        .with_filter(self.filter()?),
+                )
+                .with(file_writer.map(|writer| {
+                    tracing_subscriber::fmt::layer()
+                        .json()
+                        .with_span_events(FmtSpan::CLOSE)
+                        .with_writer(writer)
+                        .with_filter(file_filter)
+                }))
+                .try_init(),
+        };
+        installed.map_err(|e| CustomError::ConfigError(e.to_string()))?;
+        if let Some(provider) = &tracer_provider {
+            opentelemetry::global::set_tracer_provider(provider.clone());
+        }
+        Ok(LogGuard {
+            _file: guard,
+            tracer_provider,
+        })
+    }
+}
+
+/// Flushes the log file and the spans not exported yet when dropped.
+pub struct LogGuard {
+    _file: Option<WorkerGuard>,
+    tracer_provider: Option<TracerProvider>,
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = &self.tracer_provider {
+            provider.force_flush();
+        }
+    }
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Rotation {
+        match rotation {
+            LogRotation::Never => Rotation::NEVER,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Minutely => Rotation::MINUTELY,
+        }
+    }
+}
+
+#[cfg(test)]
+mod logging_tests {
+    use super::*;
+
+    fn log_config(modules: Option<&str>) -> LogConfig {
+        LogConfig {
+            level: "info".to_string(),
+            modules: modules.map(str::to_string),
+            format: LogFormat::Text,
+            file: None,
+            rotation: LogRotation::Daily,
+            access: false,
+        }
+    }
+
+    #[test]
+    fn modules_override_level() {
+        assert_eq!(
+            log_config(None).directives("info"),
+            "handle_errors=info,web=info,warp=info"
+        );
+        assert_eq!(
+            log_config(Some("warp=warn, sqlx=debug")).directives("info"),
+            "handle_errors=info,web=info,warp=warn,sqlx=debug"
+        );
+        let config = LogConfig {
+            access: true,
+            ..log_config(Some("web::access=off"))
+        };
+        assert_eq!(
+            config.directives("warn"),
+            "handle_errors=warn,web=warn,warp=warn,web::access=info,web::access=off"
+        );
+    }
+
+    #[test]
+    fn refuse_unknown_level() {
+        assert!(log_config(None).filter().is_ok());
+        assert!(log_config(Some("web=loud")).filter().is_err());
+        let config = LogConfig {
+            level: "loud".to_string(),
+            ..log_config(None)
+        };
+        assert!(config.filter().is_err());
+    }
+}
@@ -1,35 +1,64 @@
 #![warn(clippy::all)]
-use handle_errors::return_error;
-use tracing_subscriber::fmt::format::FmtSpan;
-use warp::{http::Method, Filter};
-use dotenv;
+// The routes are one deeply nested filter type.
+#![recursion_limit = "256"]
+use warp::Filter;
 
+mod body;
+mod compression;
 mod controllers;
 mod store;
 mod types;
 mod config;
+mod key_ring;
+mod logging;
+mod metrics;
+mod oidc;
+mod password;
+mod rate_limit;
+mod read_cache;
+mod request;
+mod security;
+mod server;
+mod telemetry;
 #[tokio::main]
 async fn main() -> Result<(), handle_errors::CustomError>{
 
     dotenv::dotenv().ok();
     let config = config::Config::new().expect("Cannot load Config file.");
 
-    let log_filter = format!(
-        "handle_errors={},rust_web_dev={},warp={}",
-        config.log_level, config.log_level, config.log_level
+    // Keep the guard until the end of main, it flushes the log file and
+    // the spans.
+    let _log_guard = config.log.init(&config.trace)?;
+    tracing::info!(
+        "configuration: {}",
+        serde_json::to_string(&config).unwrap_or_default()
     );
 
     // create store.
-    let store = store::Store::new(&format!(
-        "postgres://{}:{}@{}:{}/{}",
-        config.db_user,
-        config.db_password,
-        config.db_host,
-        config.db_port,
-        config.db_name
-    ))
-    .await
-    .map_err(|e| handle_errors::CustomError::DatabaseQueryError(e))?;
+    tracing::info!("connecting to {}", config.redacted_database_url());
+    let mut store = store::Store::new(config.connect_options()?, &config.pool)
+        .await
+        .map_err(handle_errors::CustomError::DatabaseQueryError)?;
+
+    if let Some(options) = config.replica_connect_options()? {
+        tracing::info!(
+            "reading from replica {}",
+            config.redacted_database_replica_url().unwrap_or_default()
+        );
+        let replica = config
+            .pool
+            .connect(options)
+            .await
+            .map_err(handle_errors::CustomError::DatabaseQueryError)?;
+        store = store.with_replica(replica);
+    }
+
+    if config.read_cache_capacity > 0 {
+        store = store.with_read_cache(read_cache::ReadCache::new(
+            config.read_cache_capacity,
+            std::time::Duration::from_secs(config.read_cache_ttl_secs),
+        ));
+    }
 
     // migrate database
     sqlx::migrate!()
@@ -38,100 +67,350 @@ async fn main() -> Result<(), handle_errors::CustomError>{
             handle_errors::CustomError::MigrationError(e) 
         })?;
 
-    let store_filter = warp::any().map(move || store.clone());
+    let key_ring = config.key_ring.clone().expect("PASETO keys not loaded");
+    let key_ring_filter = {
+        let key_ring = key_ring.clone();
+        warp::any().map(move || key_ring.clone())
+    };
+
+    let hasher_filter = {
+        let hasher = config.password_hasher.clone();
+        warp::any().map(move || hasher.clone())
+    };
 
-    tracing_subscriber::fmt()
-        // Use the filter we built above to determine which traces to record.
-        .with_env_filter(log_filter)
-        // Record an event when each span closes.
-        // This can be used to time our routes' durations!
-        .with_span_events(FmtSpan::CLOSE)
-        .init();
+    // Single sign-on routes answer "Route not found" when it is disabled.
+    let oidc_client = config.oidc.clone().map(oidc::OidcClient::new);
+    let oidc_filter = warp::any().and_then(move || {
+        let oidc_client = oidc_client.clone();
+        async move { oidc_client.ok_or_else(warp::reject::not_found) }
+    });
 
-    let cors = warp::cors()
-        .allow_any_origin()
-        .allow_header("content-type")
-        .allow_methods(&[Method::PUT, Method::DELETE, Method::GET, Method::POST]);
+    // Clients send `X-Read-Primary: true` to read their own writes when
+    // reads are served from a replica.
+    let store_filter = {
+        let store = store.clone();
+        warp::header::optional::<String>("x-read-primary")
+            .or(warp::any().map(|| None))
+            .unify()
+            .map(move |read_primary: Option<String>| {
+                match read_primary.as_deref().map(str::trim) {
+                    Some("true") | Some("1") => store.clone().primary(),
+                    _ => store.clone(),
+                }
+            })
+    };
+
+    // Token buckets of the write routes, see `[rate_limit]`.
+    let rate_limiter = rate_limit::RateLimiter::new(
+        &config.rate_limit,
+        config.listen.trusted_proxies.clone(),
+        &store,
+    );
 
-    let get_questions = warp::get()
+    // Drain on SIGTERM or SIGINT.
+    let shutdown = server::Shutdown::new();
+    tokio::spawn(shutdown.clone().on_signal());
+    let shutdown_filter = {
+        let shutdown = shutdown.clone();
+        warp::any().map(move || shutdown.clone())
+    };
+
+    let cors = config.cors.filter();
+    let security_headers = security::SecurityHeaders::new(&config.security)?;
+
+    let get_questions = config
+        .compression
+        .negotiate()
+        .and(
+            warp::get()
+                .and(warp::path("questions"))
+                .and(warp::path::end())
+                .and(warp::query())
+                .and(controllers::authentication::optional_auth(
+                    store.clone(),
+                    key_ring.clone(),
+                ))
+                .and(controllers::precondition::conditions())
+                .and(store_filter.clone())
+                .and_then(controllers::question::get_questions),
+        )
+        .and_then(compression::compress);
+
+    let get_question = warp::get()
         .and(warp::path("questions"))
+        .and(warp::path::param::<i32>())
         .and(warp::path::end())
-        .and(warp::query())
+        .and(controllers::authentication::optional_auth(
+            store.clone(),
+            key_ring.clone(),
+        ))
+        .and(controllers::precondition::conditions())
         .and(store_filter.clone())
-        .and_then(controllers::question::get_questions);
+        .and_then(controllers::question::get_question);
 
     let update_question = warp::put()
         .and(warp::path("questions"))
         .and(warp::path::param::<i32>())
         .and(warp::path::end())
-        .and(controllers::authentication::auth())
+        .and(controllers::authentication::auth(store.clone(), key_ring.clone()))
+        .and(controllers::precondition::if_match())
         .and(store_filter.clone())
+        .and(body::limit(config.body_limit.questions))
         .and(warp::body::json())
         .and_then(controllers::question::update_question);
 
+    let patch_question = warp::patch()
+        .and(warp::path("questions"))
+        .and(warp::path::param::<i32>())
+        .and(warp::path::end())
+        .and(controllers::authentication::auth(store.clone(), key_ring.clone()))
+        .and(controllers::precondition::if_match())
+        .and(store_filter.clone())
+        .and(body::limit(config.body_limit.questions))
+        .and(warp::body::json())
+        .and_then(controllers::question::patch_question);
+
     let delete_question = warp::delete()
         .and(warp::path("questions"))
         .and(warp::path::param::<i32>())
         .and(warp::path::end())
-        .and(controllers::authentication::auth())
+        .and(controllers::authentication::auth(store.clone(), key_ring.clone()))
         .and(store_filter.clone())
         .and_then(controllers::question::delete_question);
 
     let add_question = warp::post()
         .and(warp::path("questions"))
         .and(warp::path::end())
-        .and(controllers::authentication::auth())
+        .and(rate_limiter.by_account(
+            rate_limit::RouteGroup::Questions,
+            controllers::authentication::auth(store.clone(), key_ring.clone()),
+        ))
         .and(store_filter.clone())
+        .and(body::limit(config.body_limit.questions))
         .and(warp::body::json())
         .and_then(controllers::question::add_question);
 
     let add_answer = warp::post()
         .and(warp::path("answers"))
         .and(warp::path::end())
-        .and(controllers::authentication::auth())
+        .and(rate_limiter.by_account(
+            rate_limit::RouteGroup::Answers,
+            controllers::authentication::auth(store.clone(), key_ring.clone()),
+        ))
         .and(store_filter.clone())
-        .and(warp::body::form())
+        .and(body::limit(config.body_limit.answers))
+        .and(body::json_or_form())
         .and_then(controllers::answer::add_answer);
    
-    let get_question_answers = warp::get()
+    let update_answer = warp::put()
         .and(warp::path("answers"))
+        .and(warp::path::param::<i32>())
         .and(warp::path::end())
-        .and(warp::query())
+        .and(controllers::authentication::auth(store.clone(), key_ring.clone()))
+        .and(controllers::precondition::if_match())
         .and(store_filter.clone())
-        .and_then(controllers::answer::get_question_answers);
+        .and(body::limit(config.body_limit.answers))
+        .and(warp::body::json())
+        .and_then(controllers::answer::update_answer);
+
+    let get_answer = warp::get()
+        .and(warp::path("answers"))
+        .and(warp::path::param::<i32>())
+        .and(warp::path::end())
+        .and(controllers::precondition::conditions())
+        .and(store_filter.clone())
+        .and_then(controllers::answer::get_answer);
+
+    let get_question_answers = config
+        .compression
+        .negotiate()
+        .and(
+            warp::get()
+                .and(warp::path("answers"))
+                .and(warp::path::end())
+                .and(warp::query())
+                .and(controllers::precondition::conditions())
+                .and(store_filter.clone())
+                .and_then(controllers::answer::get_question_answers),
+        )
+        .and_then(compression::compress);
     
 
     let registration = warp::post()
         .and(warp::path("registration"))
         .and(warp::path::end())
+        .and(rate_limiter.by_ip(rate_limit::RouteGroup::Registration))
         .and(store_filter.clone())
+        .and(hasher_filter.clone())
+        .and(body::limit(config.body_limit.accounts))
         .and(warp::body::json())
         .and_then(controllers::authentication::register);
 
+    let get_account = warp::get()
+        .and(warp::path("accounts"))
+        .and(warp::path::param::<i32>())
+        .and(warp::path::end())
+        .and(store_filter.clone())
+        .and_then(controllers::authentication::get_account);
+
     let login = warp::post()
         .and(warp::path("login"))
         .and(warp::path::end())
         .and(store_filter.clone())
+        .and(hasher_filter.clone())
+        .and(key_ring_filter.clone())
+        .and(controllers::authentication::client_info())
+        .and(body::limit(config.body_limit.accounts))
         .and(warp::body::json())
         .and_then(controllers::authentication::login);
 
+    let add_api_token = warp::post()
+        .and(warp::path("me"))
+        .and(warp::path("tokens"))
+        .and(warp::path::end())
+        .and(controllers::authentication::auth(store.clone(), key_ring.clone()))
+        .and(store_filter.clone())
+        .and(body::limit(config.body_limit.accounts))
+        .and(warp::body::json())
+        .and_then(controllers::api_token::add_api_token);
+
+    let get_api_tokens = warp::get()
+        .and(warp::path("me"))
+        .and(warp::path("tokens"))
+        .and(warp::path::end())
+        .and(controllers::authentication::auth(store.clone(), key_ring.clone()))
+        .and(store_filter.clone())
+        .and_then(controllers::api_token::get_api_tokens);
+
+    let revoke_api_token = warp::delete()
+        .and(warp::path("me"))
+        .and(warp::path("tokens"))
+        .and(warp::path::param::<i32>())
+        .and(warp::path::end())
+        .and(controllers::authentication::auth(store.clone(), key_ring.clone()))
+        .and(store_filter.clone())
+        .and_then(controllers::api_token::revoke_api_token);
+
+    let get_sessions = warp::get()
+        .and(warp::path("me"))
+        .and(warp::path("sessions"))
+        .and(warp::path::end())
+        .and(controllers::authentication::auth(store.clone(), key_ring.clone()))
+        .and(store_filter.clone())
+        .and_then(controllers::session::get_sessions);
+
+    let revoke_session = warp::delete()
+        .and(warp::path("me"))
+        .and(warp::path("sessions"))
+        .and(warp::path::param::<uuid::Uuid>())
+        .and(warp::path::end())
+        .and(controllers::authentication::auth(store.clone(), key_ring.clone()))
+        .and(store_filter.clone())
+        .and_then(controllers::session::revoke_session);
+
+    let get_cache_stats = warp::get()
+        .and(warp::path("cache"))
+        .and(warp::path("stats"))
+        .and(warp::path::end())
+        .and(store_filter.clone())
+        .and_then(controllers::cache::get_cache_stats);
+
+    let get_public_keys = warp::get()
+        .and(warp::path(".well-known"))
+        .and(warp::path("paseto-keys"))
+        .and(warp::path::end())
+        .and(key_ring_filter.clone())
+        .and_then(controllers::authentication::get_public_keys);
+
+    let oidc_login = warp::get()
+        .and(warp::path("oidc"))
+        .and(warp::path("login"))
+        .and(warp::path::end())
+        .and(oidc_filter.clone())
+        .and_then(controllers::oidc::login);
+
+    let oidc_callback = warp::get()
+        .and(warp::path("oidc"))
+        .and(warp::path("callback"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(oidc_filter.clone())
+        .and(store_filter.clone())
+        .and(hasher_filter.clone())
+        .and(key_ring_filter.clone())
+        .and(controllers::authentication::client_info())
+        .and_then(controllers::oidc::callback);
+
+    let healthz = warp::get()
+        .and(warp::path("healthz"))
+        .and(warp::path::end())
+        .and_then(controllers::health::healthz);
+
+    let readyz = warp::get()
+        .and(warp::path("readyz"))
+        .and(warp::path::end())
+        .and(shutdown_filter)
+        .and(store_filter.clone())
+        .and_then(controllers::health::readyz);
+
+    let version = warp::get()
+        .and(warp::path("version"))
+        .and(warp::path::end())
+        .and_then(controllers::health::version);
+
+    let get_metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(store_filter.clone())
+        .and_then(controllers::metrics::get_metrics);
+
     let routes = get_questions
+        .or(get_question)
         .or(update_question)
+        .or(patch_question)
         .or(add_question)
         .or(delete_question)
         .or(add_answer)
+        .or(update_answer)
         .or(get_question_answers)
+        .or(get_answer)
         .or(registration)
+        .or(get_account)
         .or(login)
+        .or(add_api_token)
+        .or(get_api_tokens)
+        .or(revoke_api_token)
+        .or(get_sessions)
+        .or(revoke_session)
+        .or(get_public_keys)
+        .or(get_cache_stats)
+        .or(oidc_login)
+        .or(oidc_callback)
+        .or(healthz)
+        .or(readyz)
+        .or(version)
+        .or(get_metrics)
         .with(cors)
-        .with(warp::trace::request())
-        .recover(return_error);
+        .recover(metrics::recover);
+
+    // Every request gets an id, an access log line and the security
+    // headers, also when rejected.
+    let routes = request::start()
+        .and(routes)
+        .map(request::finish)
+        .map(move |response| security_headers.apply(response))
+        .with(warp::log::custom(metrics::record_request))
+        .with(warp::trace(telemetry::request_span));
 
 
     tracing::info!("Q&A service build ID {}", env!("RUST_WEB_DEV_VERSION"));
     
-    /// run server
-    warp::serve(routes).run(([127, 0, 0, 1], config.port)).await;
+    // run server
+    tracing::info!("listening on {}", config.listen.describe(config.port));
+    config.listen.serve(config.port, routes, shutdown).await?;
+
+    store.close().await;
+    tracing::info!("database connections closed, bye");
 
     Ok(())
 }
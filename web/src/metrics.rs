@@ -0,0 +1,287 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounter,
+    IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use sqlx::PgPool;
+use std::time::Duration;
+use warp::{reply::Response, Rejection};
+
+use handle_errors::{return_error, CustomError};
+
+use crate::store::Store;
+
+/// Routes the requests are counted by, `{id}` standing for any segment.
+/// Other paths are counted as `unmatched`, so scanners can't add labels.
+const ROUTES: [&str; 19] = [
+    "/questions",
+    "/questions/{id}",
+    "/answers",
+    "/answers/{id}",
+    "/registration",
+    "/accounts/{id}",
+    "/login",
+    "/me/tokens",
+    "/me/tokens/{id}",
+    "/me/sessions",
+    "/me/sessions/{id}",
+    "/cache/stats",
+    "/.well-known/paseto-keys",
+    "/oidc/login",
+    "/oidc/callback",
+    "/healthz",
+    "/readyz",
+    "/version",
+    "/metrics",
+];
+
+/// The metrics of the server, registered in their own registry.
+struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    query_duration: HistogramVec,
+    errors: IntCounterVec,
+    questions_created: IntCounter,
+    answers_created: IntCounter,
+    registrations: IntCounter,
+    pool_connections: IntGaugeVec,
+    pool_idle_connections: IntGaugeVec,
+    pool_acquire: HistogramVec,
+}
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new();
+        let metrics = Metrics {
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests answered"),
+                &["route", "method", "status"],
+            )
+            .unwrap(),
+            request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "Time taken to answer HTTP requests",
+                ),
+                &["route", "method", "status"],
+            )
+            .unwrap(),
+            query_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "db_query_duration_seconds",
+                    "Time taken by the database queries of each store method",
+                ),
+                &["method"],
+            )
+            .unwrap(),
+            errors: IntCounterVec::new(
+                Opts::new("errors_total", "Requests rejected, by error"),
+                &["error"],
+            )
+            .unwrap(),
+            questions_created: IntCounter::new(
+                "questions_created_total",
+                "Questions asked",
+            )
+            .unwrap(),
+            answers_created: IntCounter::new(
+                "answers_created_total",
+                "Answers given",
+            )
+            .unwrap(),
+            registrations: IntCounter::new(
+                "registrations_total",
+                "Accounts registered",
+            )
+            .unwrap(),
+            pool_connections: IntGaugeVec::new(
+                Opts::new("db_pool_connections", "Open database connections"),
+                &["pool"],
+            )
+            .unwrap(),
+            pool_idle_connections: IntGaugeVec::new(
+                Opts::new(
+                    "db_pool_idle_connections",
+                    "Open database connections not in use",
+                ),
+                &["pool"],
+            )
+            .unwrap(),
+            pool_acquire: HistogramVec::new(
+                HistogramOpts::new(
+                    "db_pool_acquire_seconds",
+                    "Time the queries waited for a database connection",
+                ),
+                &["pool"],
+            )
+            .unwrap(),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 10] = [
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.request_duration.clone()),
+            Box::new(metrics.query_duration.clone()),
+            Box::new(metrics.errors.clone()),
+            Box::new(metrics.questions_created.clone()),
+            Box::new(metrics.answers_created.clone()),
+            Box::new(metrics.registrations.clone()),
+            Box::new(metrics.pool_connections.clone()),
+            Box::new(metrics.pool_idle_connections.clone()),
+            Box::new(metrics.pool_acquire.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+}
+
+/// Counts and times an answered request, to be used with
+/// `warp::log::custom` around the recovered routes.
+pub fn record_request(info: warp::log::Info) {
+    let route = route(info.path());
+    let status = info.status().as_u16().to_string();
+    let labels = [route, info.method().as_str(), &status];
+    METRICS.requests.with_label_values(&labels).inc();
+    METRICS
+        .request_duration
+        .with_label_values(&labels)
+        .observe(info.elapsed().as_secs_f64());
+}
+
+/// Counts the `CustomError` a request was rejected with, then answers it
+/// like `return_error`.
+pub async fn recover(r: Rejection) -> Result<Response, Rejection> {
+    if let Some(error) = r.find::<CustomError>() {
+        METRICS.errors.with_label_values(&[error.variant()]).inc();
+    }
+    return_error(r).await
+}
+
+/// Times the database queries of a store method until dropped.
+pub fn query_timer(method: &str) -> HistogramTimer {
+    METRICS.query_duration.with_label_values(&[method]).start_timer()
+}
+
+/// Records how long a query waited for a connection of a pool.
+pub fn pool_acquired(pool: &str, waited: Duration) {
+    METRICS
+        .pool_acquire
+        .with_label_values(&[pool])
+        .observe(waited.as_secs_f64());
+}
+
+pub fn question_created() {
+    METRICS.questions_created.inc();
+}
+
+pub fn answer_created() {
+    METRICS.answers_created.inc();
+}
+
+pub fn account_registered() {
+    METRICS.registrations.inc();
+}
+
+/// The metrics in the Prometheus text format, with the pool gauges read
+/// from the store's pools.
+pub fn render(store: &Store) -> Result<String, CustomError> {
+    observe_pool("primary", &store.connection);
+    if let Some(replica) = &store.replica {
+        observe_pool("replica", replica);
+    }
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&METRICS.registry.gather(), &mut buffer)
+        .map_err(|e| {
+            CustomError::ServerError(handle_errors::APILayerError {
+                status: 500,
+                message: e.to_string(),
+            })
+        })?;
+    String::from_utf8(buffer).map_err(|e| {
+        CustomError::ServerError(handle_errors::APILayerError {
+            status: 500,
+            message: e.to_string(),
+        })
+    })
+}
+
+/// Updates the size gauges of a pool.
+fn observe_pool(name: &str, pool: &PgPool) {
+    METRICS
+        .pool_connections
+        .with_label_values(&[name])
+        .set(pool.size().into());
+    METRICS
+        .pool_idle_connections
+        .with_label_values(&[name])
+        .set(pool.num_idle() as i64);
+}
+
+/// The route template of a request path, `unmatched` when none fits.
+pub fn route(path: &str) -> &'static str {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    ROUTES
+        .iter()
+        .find(|route| {
+            let parts: Vec<&str> = route.trim_start_matches('/').split('/').collect();
+            parts.len() == segments.len()
+                && parts.iter().zip(&segments).all(|(part, segment)| {
+                    part == segment || (*part == "{id}" && !segment.is_empty())
+                })
+        })
+        .copied()
+        .unwrap_or("unmatched")
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use super::*;
+
+    #[test]
+    fn label_requests_by_route() {
+        assert_eq!(route("/questions"), "/questions");
+        assert_eq!(route("/questions/"), "/questions");
+        assert_eq!(route("/questions/42"), "/questions/{id}");
+        assert_eq!(
+            route("/me/sessions/5f0c2b9e-8d4e-4a4b-9b1e-2d6f0f6e3a11"),
+            "/me/sessions/{id}"
+        );
+        assert_eq!(route("/questions/42/answers"), "unmatched");
+        assert_eq!(route("/wp-login.php"), "unmatched");
+    }
+
+    #[tokio::test]
+    async fn count_errors_by_variant() {
+        let errors = || {
+            METRICS
+                .errors
+                .with_label_values(&["PreconditionFailed"])
+                .get()
+        };
+        let before = errors();
+        let response = recover(warp::reject::custom(CustomError::PreconditionFailed))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 412);
+        assert_eq!(errors(), before + 1);
+    }
+
+    #[tokio::test]
+    async fn render_text_format() {
+        question_created();
+        let _ = query_timer("get_questions");
+        pool_acquired("primary", Duration::from_millis(2));
+        let text = render(&Store::lazy()).unwrap();
+        assert!(text.contains("# TYPE questions_created_total counter"));
+        assert!(text.contains("db_query_duration_seconds_count{method=\"get_questions\"}"));
+        assert!(text.contains("db_pool_connections{pool=\"primary\"} 0"));
+        assert!(text.contains("db_pool_acquire_seconds_count{pool=\"primary\"}"));
+    }
+}
@@ -0,0 +1,345 @@
+use rand::Rng;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use handle_errors::CustomError;
+
+/// How long a started login may take before its state is forgotten.
+const PENDING_LOGIN_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Settings of the OpenID Connect identity provider. Endpoints are
+/// configured one by one so tests can point them to a local mock provider.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OidcConfig {
+    /// Client id registered at the provider.
+    pub client_id: String,
+    /// Client secret, `None` for public clients relying on PKCE only.
+    pub client_secret: Option<String>,
+    /// URL users are redirected to for signing in.
+    pub authorization_endpoint: String,
+    /// URL the authorization code is exchanged at.
+    pub token_endpoint: String,
+    /// URL returning the claims of the signed in user.
+    pub userinfo_endpoint: String,
+    /// Our `/oidc/callback` URL, as registered at the provider.
+    pub redirect_uri: String,
+    /// Scopes requested, separated by spaces.
+    pub scopes: String,
+}
+
+impl OidcConfig {
+    /// Loads the provider settings from the environment. Returns `None`
+    /// when `OIDC_CLIENT_ID` is not set, which disables single sign-on.
+    pub fn from_env() -> Result<Option<OidcConfig>, CustomError> {
+        let client_id = match env::var("OIDC_CLIENT_ID") {
+            Ok(client_id) => client_id,
+            Err(_) => return Ok(None),
+        };
+
+        let required = |name: &str| {
+            env::var(name).map_err(|_| {
+                CustomError::ConfigError(format!(
+                    "{} must be set when OIDC_CLIENT_ID is",
+                    name
+                ))
+            })
+        };
+
+        Ok(Some(OidcConfig {
+            client_id,
+            client_secret: env::var("OIDC_CLIENT_SECRET").ok(),
+            authorization_endpoint: required("OIDC_AUTHORIZATION_ENDPOINT")?,
+            token_endpoint: required("OIDC_TOKEN_ENDPOINT")?,
+            userinfo_endpoint: required("OIDC_USERINFO_ENDPOINT")?,
+            redirect_uri: required("OIDC_REDIRECT_URI")?,
+            scopes: env::var("OIDC_SCOPES")
+                .unwrap_or_else(|_| "openid email profile".to_string()),
+        }))
+    }
+}
+
+/// A login started at `/oidc/login`, waiting for its callback.
+struct PendingLogin {
+    code_verifier: String,
+    started: Instant,
+}
+
+/// The claims of the userinfo endpoint we rely on.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct UserInfo {
+    /// Identifier of the user at the provider.
+    pub sub: String,
+    /// Email address of the user.
+    pub email: Option<String>,
+    /// Whether the provider verified the email address.
+    #[serde(default)]
+    pub email_verified: bool,
+}
+
+/// Response of the token endpoint.
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    access_token: String,
+}
+
+/// Runs the authorization code flow with PKCE against the identity
+/// provider. Cheap to clone, pending logins are shared.
+#[derive(Clone)]
+pub struct OidcClient {
+    config: OidcConfig,
+    http: reqwest::Client,
+    pending: Arc<Mutex<HashMap<String, PendingLogin>>>,
+}
+
+impl std::fmt::Debug for OidcClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OidcClient")
+            .field("client_id", &self.config.client_id)
+            .finish()
+    }
+}
+
+impl OidcClient {
+    /// Creates a client for the configured provider.
+    pub fn new(config: OidcConfig) -> OidcClient {
+        OidcClient {
+            config,
+            http: reqwest::Client::new(),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Starts a login: remembers a fresh state and PKCE verifier, and
+    /// returns the provider URL the user has to be redirected to.
+    pub fn start_login(&self) -> Result<String, CustomError> {
+        let state = random_urlsafe();
+        let code_verifier = random_urlsafe();
+        let code_challenge = base64::encode_config(
+            Sha256::digest(code_verifier.as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        );
+
+        let url = reqwest::Url::parse_with_params(
+            &self.config.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", self.config.redirect_uri.as_str()),
+                ("scope", self.config.scopes.as_str()),
+                ("state", state.as_str()),
+                ("code_challenge", code_challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| CustomError::OidcError(e.to_string()))?;
+
+        let mut pending = self.pending.lock().expect("pending logins poisoned");
+        pending.retain(|_, login| login.started.elapsed() < PENDING_LOGIN_TTL);
+        pending.insert(
+            state,
+            PendingLogin {
+                code_verifier,
+                started: Instant::now(),
+            },
+        );
+
+        Ok(url.to_string())
+    }
+
+    /// Finishes a login: checks the state, exchanges the code for an
+    /// access token and returns the claims of the user.
+    pub async fn finish_login(
+        &self,
+        state: &str,
+        code: &str,
+    ) -> Result<UserInfo, CustomError> {
+        let login = self
+            .pending
+            .lock()
+            .expect("pending logins poisoned")
+            .remove(state)
+            .filter(|login| login.started.elapsed() < PENDING_LOGIN_TTL)
+            .ok_or_else(|| {
+                CustomError::OidcError("unknown or expired state".to_string())
+            })?;
+
+        let access_token = self.exchange_code(code, &login.code_verifier).await?;
+        self.user_info(&access_token).await
+    }
+
+    /// Exchanges an authorization code at the token endpoint.
+    async fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+    ) -> Result<String, CustomError> {
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.config.redirect_uri.as_str()),
+            ("client_id", self.config.client_id.as_str()),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+
+        let res = self
+            .http
+            .post(&self.config.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .map_err(CustomError::ReqwestAPIError)?;
+
+        if !res.status().is_success() {
+            return Err(CustomError::OidcError(format!(
+                "token endpoint answered {}",
+                res.status()
+            )));
+        }
+
+        res.json::<TokenResponse>()
+            .await
+            .map(|token| token.access_token)
+            .map_err(CustomError::ReqwestAPIError)
+    }
+
+    /// Fetches the claims of the user owning the access token.
+    async fn user_info(&self, access_token: &str) -> Result<UserInfo, CustomError> {
+        let res = self
+            .http
+            .get(&self.config.userinfo_endpoint)
+            .bearer_auth(access_token)
+            .send()
+            .await
+            .map_err(CustomError::ReqwestAPIError)?;
+
+        if !res.status().is_success() {
+            return Err(CustomError::OidcError(format!(
+                "userinfo endpoint answered {}",
+                res.status()
+            )));
+        }
+
+        res.json::<UserInfo>()
+            .await
+            .map_err(CustomError::ReqwestAPIError)
+    }
+}
+
+/// 32 random bytes, base64url encoded, used for states and verifiers.
+fn random_urlsafe() -> String {
+    let bytes = rand::thread_rng().gen::<[u8; 32]>();
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod oidc_tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use warp::Filter;
+
+    /// Starts a mock identity provider which accepts the code `good-code`
+    /// when the PKCE verifier matches the challenge it was given.
+    async fn mock_provider(challenge: Arc<Mutex<String>>) -> SocketAddr {
+        let token = warp::post()
+            .and(warp::path("token"))
+            .and(warp::body::form())
+            .map(move |form: HashMap<String, String>| {
+                let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+                let expected = base64::encode_config(
+                    Sha256::digest(verifier.as_bytes()),
+                    base64::URL_SAFE_NO_PAD,
+                );
+                if form.get("code").map(String::as_str) == Some("good-code")
+                    && *challenge.lock().unwrap() == expected
+                {
+                    warp::reply::with_status(
+                        warp::reply::json(&serde_json::json!({
+                            "access_token": "at-123",
+                            "token_type": "Bearer"
+                        })),
+                        warp::http::StatusCode::OK,
+                    )
+                } else {
+                    warp::reply::with_status(
+                        warp::reply::json(&serde_json::json!({"error": "invalid_grant"})),
+                        warp::http::StatusCode::BAD_REQUEST,
+                    )
+                }
+            });
+        let userinfo = warp::get()
+            .and(warp::path("userinfo"))
+            .and(warp::header::<String>("authorization"))
+            .map(|auth: String| {
+                assert_eq!(auth, "Bearer at-123");
+                warp::reply::json(&serde_json::json!({
+                    "sub": "42",
+                    "email": "vanhg@corp.example",
+                    "email_verified": true
+                }))
+            });
+
+        let (addr, server) =
+            warp::serve(token.or(userinfo)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
+
+    fn client(addr: SocketAddr) -> OidcClient {
+        OidcClient::new(OidcConfig {
+            client_id: "rust-web-dev".to_string(),
+            client_secret: None,
+            authorization_endpoint: format!("http://{}/authorize", addr),
+            token_endpoint: format!("http://{}/token", addr),
+            userinfo_endpoint: format!("http://{}/userinfo", addr),
+            redirect_uri: "http://localhost:3000/oidc/callback".to_string(),
+            scopes: "openid email".to_string(),
+        })
+    }
+
+    fn query_param(url: &str, name: &str) -> String {
+        reqwest::Url::parse(url)
+            .unwrap()
+            .query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn full_login_against_mock_provider() {
+        let challenge = Arc::new(Mutex::new(String::new()));
+        let client = client(mock_provider(challenge.clone()).await);
+
+        let url = client.start_login().unwrap();
+        assert_eq!(query_param(&url, "code_challenge_method"), "S256");
+        *challenge.lock().unwrap() = query_param(&url, "code_challenge");
+        let state = query_param(&url, "state");
+
+        let user = client.finish_login(&state, "good-code").await.unwrap();
+        assert_eq!(user.email.as_deref(), Some("vanhg@corp.example"));
+        assert!(user.email_verified);
+
+        // A state can only be used once.
+        assert!(client.finish_login(&state, "good-code").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_state_and_bad_code() {
+        let challenge = Arc::new(Mutex::new(String::new()));
+        let client = client(mock_provider(challenge.clone()).await);
+
+        assert!(client.finish_login("forged", "good-code").await.is_err());
+
+        let url = client.start_login().unwrap();
+        *challenge.lock().unwrap() = query_param(&url, "code_challenge");
+        let state = query_param(&url, "state");
+        assert!(client.finish_login(&state, "bad-code").await.is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use argon2::{Variant, Version};
+use rand::Rng;
+use serde::{Serialize, Serializer};
+
+use handle_errors::CustomError;
+
+/// Argon2 settings used to hash passwords. The defaults follow the OWASP
+/// recommendation for argon2id: 19 MiB of memory, 2 iterations, 1 lane.
+#[derive(clap::Args, Serialize, Clone, Debug, PartialEq)]
+pub struct PasswordHasher {
+    /// Memory used to hash a password, in KiB
+    #[clap(
+        name = "argon2-memory-kib",
+        long = "argon2-memory-kib",
+        env = "ARGON2_MEMORY_KIB",
+        default_value = "19456"
+    )]
+    pub memory_kib: u32,
+    /// Number of passes over the memory
+    #[clap(
+        name = "argon2-iterations",
+        long = "argon2-iterations",
+        env = "ARGON2_ITERATIONS",
+        default_value = "2"
+    )]
+    pub iterations: u32,
+    /// Number of lanes
+    #[clap(
+        name = "argon2-parallelism",
+        long = "argon2-parallelism",
+        env = "ARGON2_PARALLELISM",
+        default_value = "1"
+    )]
+    pub parallelism: u32,
+    /// Argon2 variant (argon2id, argon2i or argon2d)
+    #[clap(
+        name = "argon2-variant",
+        long = "argon2-variant",
+        env = "ARGON2_VARIANT",
+        default_value = "argon2id",
+        parse(try_from_str = parse_variant)
+    )]
+    #[serde(serialize_with = "serialize_variant")]
+    pub variant: Variant,
+}
+
+/// Settings read from an encoded hash, `$argon2id$v=19$m=..,t=..,p=..$..`.
+#[derive(Debug, PartialEq)]
+struct HashParams {
+    variant: Variant,
+    version: u32,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+impl PasswordHasher {
+    /// Checks argon2 accepts the settings.
+    pub fn validate(&self) -> Result<(), CustomError> {
+        if self.iterations < 1 || self.parallelism < 1 {
+            return Err(CustomError::ConfigError(
+                "argon2 iterations and parallelism must be at least 1"
+                    .to_string(),
+            ));
+        }
+        if self.memory_kib < 8 * self.parallelism {
+            return Err(CustomError::ConfigError(format!(
+                "argon2 memory must be at least {} KiB",
+                8 * self.parallelism
+            )));
+        }
+        Ok(())
+    }
+
+    fn argon2_config(&self) -> argon2::Config<'static> {
+        argon2::Config {
+            variant: self.variant,
+            version: Version::Version13,
+            mem_cost: self.memory_kib,
+            time_cost: self.iterations,
+            lanes: self.parallelism,
+            ..argon2::Config::default()
+        }
+    }
+
+    /// Hashes a password on the blocking thread pool, so the expensive
+    /// computation doesn't stall the runtime.
+    pub async fn hash(&self, password: Vec<u8>) -> Result<String, CustomError> {
+        let hasher = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let salt = rand::thread_rng().gen::<[u8; 32]>();
+            argon2::hash_encoded(&password, &salt, &hasher.argon2_config())
+        })
+        .await
+        .expect("password hashing panicked")
+        .map_err(CustomError::ArgonLibraryError)
+    }
+
+    /// Verifies a password against an encoded hash on the blocking thread
+    /// pool. The settings come from the hash itself. Values which are not
+    /// hashes, like the `!` of placeholder accounts, match no password.
+    pub async fn verify(
+        &self,
+        hash: String,
+        password: Vec<u8>,
+    ) -> Result<bool, CustomError> {
+        match tokio::task::spawn_blocking(move || {
+            argon2::verify_encoded(&hash, &password)
+        })
+        .await
+        .expect("password verification panicked")
+        {
+            Ok(verified) => Ok(verified),
+            Err(argon2::Error::DecodingFail) => Ok(false),
+            Err(e) => Err(CustomError::ArgonLibraryError(e)),
+        }
+    }
+
+    /// Whether a hash was made with other settings than the current ones,
+    /// in which case the password should be hashed again.
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let current = HashParams {
+            variant: self.variant,
+            version: Version::Version13.as_u32(),
+            memory_kib: self.memory_kib,
+            iterations: self.iterations,
+            parallelism: self.parallelism,
+        };
+        parse_hash(hash) != Some(current)
+    }
+}
+
+fn serialize_variant<S: Serializer>(
+    variant: &Variant,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(variant.as_lowercase_str())
+}
+
+fn parse_variant(name: &str) -> Result<Variant, String> {
+    Variant::from_str(name)
+        .map_err(|_| format!("unknown argon2 variant {:?}", name))
+}
+
+/// Reads the settings of an encoded hash, `None` when it isn't one.
+fn parse_hash(hash: &str) -> Option<HashParams> {
+    let mut parts = hash.split('$');
+    if !parts.next()?.is_empty() {
+        return None;
+    }
+    let variant = parse_variant(parts.next()?).ok()?;
+    let version = parts.next()?.strip_prefix("v=")?.parse().ok()?;
+
+    let mut memory_kib = None;
+    let mut iterations = None;
+    let mut parallelism = None;
+    for option in parts.next()?.split(',') {
+        let (name, value) = option.split_once('=')?;
+        let value = value.parse::<u32>().ok()?;
+        match name {
+            "m" => memory_kib = Some(value),
+            "t" => iterations = Some(value),
+            "p" => parallelism = Some(value),
+            _ => return None,
+        }
+    }
+
+    Some(HashParams {
+        variant,
+        version,
+        memory_kib: memory_kib?,
+        iterations: iterations?,
+        parallelism: parallelism?,
+    })
+}
+
+#[cfg(test)]
+mod password_tests {
+    use super::*;
+
+    fn hasher() -> PasswordHasher {
+        PasswordHasher {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+            variant: Variant::Argon2id,
+        }
+    }
+
+    #[test]
+    fn parse_encoded_hash() {
+        assert_eq!(
+            parse_hash("$argon2i$v=19$m=4096,t=3,p=1$c2FsdHNhbHQ$aGFzaA"),
+            Some(HashParams {
+                variant: Variant::Argon2i,
+                version: 19,
+                memory_kib: 4096,
+                iterations: 3,
+                parallelism: 1,
+            })
+        );
+        assert_eq!(parse_hash("not a hash"), None);
+        assert_eq!(parse_hash("$argon2id$v=19$m=64,t=1$salt$hash"), None);
+    }
+
+    #[tokio::test]
+    async fn hash_verify_and_rehash() {
+        let hasher = hasher();
+        let hash = hasher.hash(b"secret".to_vec()).await.unwrap();
+        assert!(hasher.verify(hash.clone(), b"secret".to_vec()).await.unwrap());
+        assert!(!hasher.verify(hash.clone(), b"wrong".to_vec()).await.unwrap());
+        assert!(!hasher.needs_rehash(&hash));
+        assert!(!hasher.verify("!".to_string(), b"!".to_vec()).await.unwrap());
+
+        // Hashes made with the old `argon2::Config::default()`.
+        let legacy = argon2::hash_encoded(
+            b"secret",
+            b"saltsaltsalt",
+            &argon2::Config::default(),
+        )
+        .unwrap();
+        assert!(hasher.needs_rehash(&legacy));
+
+        let stronger = PasswordHasher {
+            iterations: 2,
+            ..hasher
+        };
+        assert!(stronger.needs_rehash(&hash));
+    }
+
+    #[test]
+    fn refuse_too_little_memory() {
+        let hasher = PasswordHasher {
+            memory_kib: 8,
+            parallelism: 2,
+            ..hasher()
+        };
+        assert!(hasher.validate().is_err());
+    }
+}
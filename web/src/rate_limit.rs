@@ -0,0 +1,552 @@
+use hashlink::LruCache;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::Filter;
+
+use handle_errors::{CustomError, RateLimitExceeded};
+
+use crate::server::TrustedProxies;
+use crate::store::Store;
+use crate::types::account::Session;
+
+/// Buckets kept in memory. Past that, the least recently used bucket is
+/// forgotten, and its client starts again with a full one.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Client of requests whose address is unknown: on a Unix socket, when
+/// the proxy sent no `X-Forwarded-For`. These clients share one bucket.
+const UNKNOWN_CLIENT: &str = "unknown";
+
+/// Checks between two clean ups of the shared buckets.
+const PRUNE_EVERY: u64 = 1_000;
+
+/// At most `requests` requests in a burst, given back one by one over
+/// `period`. Written `20/min`, with `s`, `min`, `h` or `d`, or `off`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    pub fn is_off(&self) -> bool {
+        self.requests == 0
+    }
+
+    fn per_sec(&self) -> f64 {
+        f64::from(self.requests) / self.period.as_secs_f64()
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RateLimit, String> {
+        let invalid = || format!("invalid rate limit {:?}, e.g. 20/min or off", s);
+        if s.trim() == "off" {
+            return Ok(RateLimit {
+                requests: 0,
+                period: Duration::from_secs(1),
+            });
+        }
+        let (requests, unit) = s.trim().split_once('/').ok_or_else(invalid)?;
+        let secs = match unit.trim() {
+            "s" => 1,
+            "min" => 60,
+            "h" => 3600,
+            "d" => 86400,
+            _ => return Err(invalid()),
+        };
+        Ok(RateLimit {
+            requests: requests.trim().parse().map_err(|_| invalid())?,
+            period: Duration::from_secs(secs),
+        })
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unit = match self.period.as_secs() {
+            _ if self.is_off() => return f.write_str("off"),
+            1 => "s",
+            60 => "min",
+            3600 => "h",
+            _ => "d",
+        };
+        write!(f, "{}/{}", self.requests, unit)
+    }
+}
+
+impl Serialize for RateLimit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Where the buckets are kept.
+#[derive(clap::ArgEnum, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BucketStore {
+    /// In the memory of each instance.
+    Memory,
+    /// In the `rate_limits` table, shared by all instances.
+    Postgres,
+}
+
+/// Rate limits of the write routes, the `[rate_limit]` section of the
+/// configuration file.
+#[derive(clap::Args, Serialize, Clone, Debug, PartialEq)]
+pub struct RateLimitConfig {
+    /// Questions an account may ask, e.g. `20/min`, or `off`
+    #[clap(
+        name = "rate-limit-questions",
+        long = "rate-limit-questions",
+        env = "RATE_LIMIT_QUESTIONS",
+        default_value = "20/min"
+    )]
+    pub questions: RateLimit,
+    /// Answers an account may give
+    #[clap(
+        name = "rate-limit-answers",
+        long = "rate-limit-answers",
+        env = "RATE_LIMIT_ANSWERS",
+        default_value = "60/min"
+    )]
+    pub answers: RateLimit,
+    /// Registrations from one client address
+    #[clap(
+        name = "rate-limit-registration",
+        long = "rate-limit-registration",
+        env = "RATE_LIMIT_REGISTRATION",
+        default_value = "10/h"
+    )]
+    pub registration: RateLimit,
+    /// Where the buckets are kept: `memory`, or `postgres` to share them
+    /// between instances
+    #[clap(
+        name = "rate-limit-store",
+        long = "rate-limit-store",
+        env = "RATE_LIMIT_STORE",
+        arg_enum,
+        default_value = "memory"
+    )]
+    pub store: BucketStore,
+}
+
+/// Routes sharing a rate limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RouteGroup {
+    Questions,
+    Answers,
+    Registration,
+}
+
+impl RouteGroup {
+    fn name(&self) -> &'static str {
+        match self {
+            RouteGroup::Questions => "questions",
+            RouteGroup::Answers => "answers",
+            RouteGroup::Registration => "registration",
+        }
+    }
+}
+
+/// A token bucket, full when unused.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: &RateLimit, now: Instant) -> Bucket {
+        Bucket {
+            tokens: f64::from(limit.requests),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_sec()).min(f64::from(limit.requests));
+        self.updated = now;
+    }
+
+    /// Takes a token, or tells how long until one is back.
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(retry_after(self.tokens, limit))
+        }
+    }
+
+    fn is_full(&self, limit: &RateLimit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * limit.per_sec() >= f64::from(limit.requests)
+    }
+}
+
+/// Time until a bucket holding `tokens` has one.
+fn retry_after(tokens: f64, limit: &RateLimit) -> Duration {
+    Duration::from_secs_f64(((1.0 - tokens) / limit.per_sec()).max(0.0))
+}
+
+/// Counts the requests of each client against the limits, in memory or
+/// in the database. Cheap to clone, clones share the buckets.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    trusted_proxies: TrustedProxies,
+    buckets: Arc<Mutex<LruCache<(RouteGroup, String), Bucket>>>,
+    store: Option<Store>,
+    checks: Arc<AtomicU64>,
+}
+
+impl RateLimiter {
+    /// Limits the requests as `config` says. `store` keeps the buckets
+    /// when they are shared.
+    pub fn new(
+        config: &RateLimitConfig,
+        trusted_proxies: TrustedProxies,
+        store: &Store,
+    ) -> RateLimiter {
+        RateLimiter {
+            config: config.clone(),
+            trusted_proxies,
+            buckets: Arc::new(Mutex::new(LruCache::new(MAX_BUCKETS))),
+            store: match config.store {
+                BucketStore::Memory => None,
+                BucketStore::Postgres => Some(store.clone()),
+            },
+            checks: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn limit(&self, group: RouteGroup) -> RateLimit {
+        match group {
+            RouteGroup::Questions => self.config.questions,
+            RouteGroup::Answers => self.config.answers,
+            RouteGroup::Registration => self.config.registration,
+        }
+    }
+
+    /// Limits the routes by the address of the client, as told by the
+    /// trusted proxies.
+    pub fn by_ip(
+        &self,
+        group: RouteGroup,
+    ) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+        let limiter = self.clone();
+        warp::addr::remote()
+            .and(
+                warp::header::optional::<String>("x-forwarded-for")
+                    .or(warp::any().map(|| None))
+                    .unify(),
+            )
+            .and_then(
+                move |peer: Option<SocketAddr>, forwarded_for: Option<String>| {
+                    let limiter = limiter.clone();
+                    async move {
+                        let client = limiter.client_key(peer, forwarded_for.as_deref());
+                        limiter.check(group, client).await
+                    }
+                },
+            )
+            .untuple_one()
+    }
+
+    /// The bucket of a client limited by address, e.g. `ip:198.51.100.7`.
+    /// Without a peer nor `X-Forwarded-For`, e.g. behind a local proxy on
+    /// the Unix socket which doesn't set the header, every client is
+    /// `ip:unknown` and they share a bucket.
+    fn client_key(&self, peer: Option<SocketAddr>, forwarded_for: Option<&str>) -> String {
+        let client = self
+            .trusted_proxies
+            .client_ip(peer.map(|peer| peer.ip()), forwarded_for);
+        format!(
+            "ip:{}",
+            client.map_or_else(|| UNKNOWN_CLIENT.to_string(), |ip: IpAddr| ip.to_string())
+        )
+    }
+
+    /// Limits the routes by the account of the session `auth` extracts.
+    pub fn by_account<F>(
+        &self,
+        group: RouteGroup,
+        auth: F,
+    ) -> impl Filter<Extract = (Session,), Error = warp::Rejection> + Clone
+    where
+        F: Filter<Extract = (Session,), Error = warp::Rejection> + Clone,
+    {
+        let limiter = self.clone();
+        auth.and_then(move |session: Session| {
+            let limiter = limiter.clone();
+            async move {
+                limiter
+                    .check(group, format!("account:{}", session.account_id.0))
+                    .await?;
+                Ok::<_, warp::Rejection>(session)
+            }
+        })
+    }
+
+    /// Takes a token from the bucket of `client` for the group, rejecting
+    /// the request with `RateLimited` when it is empty.
+    async fn check(&self, group: RouteGroup, client: String) -> Result<(), warp::Rejection> {
+        let limit = self.limit(group);
+        if limit.is_off() {
+            return Ok(());
+        }
+        let taken = match &self.store {
+            Some(store) => {
+                let bucket = format!("{}:{}", group.name(), client);
+                self.take_shared(store, bucket, &limit).await
+            }
+            None => self.take(group, client, Instant::now()),
+        };
+        taken.map_err(|wait| {
+            warp::reject::custom(CustomError::RateLimited(RateLimitExceeded {
+                limit: limit.requests,
+                retry_after_secs: wait.as_secs_f64().ceil() as u64,
+            }))
+        })
+    }
+
+    /// Takes a token from a bucket in memory. New buckets push out the
+    /// least recently used one once `MAX_BUCKETS` are kept.
+    fn take(&self, group: RouteGroup, client: String, now: Instant) -> Result<(), Duration> {
+        let limit = self.limit(group);
+        let key = (group, client);
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(bucket) = buckets.get_mut(&key) {
+            return bucket.take(&limit, now);
+        }
+        if buckets.len() >= buckets.capacity() {
+            if let Some(((evicted_group, _), evicted)) = buckets.remove_lru() {
+                if !evicted.is_full(&self.limit(evicted_group), now) {
+                    tracing::warn!(
+                        "{} rate limit buckets in use, a {} client got its burst back",
+                        MAX_BUCKETS,
+                        evicted_group.name()
+                    );
+                }
+            }
+        }
+        let mut bucket = Bucket::full(&limit, now);
+        let taken = bucket.take(&limit, now);
+        buckets.insert(key, bucket);
+        taken
+    }
+
+    /// Takes a token from a bucket in the database. Requests are let
+    /// through when the database fails, they would fail anyway.
+    async fn take_shared(
+        &self,
+        store: &Store,
+        bucket: String,
+        limit: &RateLimit,
+    ) -> Result<(), Duration> {
+        if self
+            .checks
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(PRUNE_EVERY)
+        {
+            let longest = [
+                self.config.questions,
+                self.config.answers,
+                self.config.registration,
+            ]
+            .iter()
+            .map(|limit| limit.period)
+            .max()
+            .unwrap_or_default();
+            tokio::spawn(store.clone().prune_rate_limits(longest));
+        }
+
+        match store
+            .clone()
+            .take_rate_limit_token(bucket, f64::from(limit.requests), limit.per_sec())
+            .await
+        {
+            Ok((true, _)) => Ok(()),
+            Ok((false, tokens)) => Err(retry_after(tokens, limit)),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod rate_limit_tests {
+    use super::*;
+
+    fn config(store: BucketStore) -> RateLimitConfig {
+        RateLimitConfig {
+            questions: "2/min".parse().unwrap(),
+            answers: "off".parse().unwrap(),
+            registration: "1/h".parse().unwrap(),
+            store,
+        }
+    }
+
+    #[test]
+    fn parse_limits() {
+        let limit: RateLimit = "20/min".parse().unwrap();
+        assert_eq!(limit.requests, 20);
+        assert_eq!(limit.period, Duration::from_secs(60));
+        assert_eq!(limit.to_string(), "20/min");
+        assert!("off".parse::<RateLimit>().unwrap().is_off());
+        assert_eq!("0/s".parse::<RateLimit>().unwrap().to_string(), "off");
+        assert!("20 per minute".parse::<RateLimit>().is_err());
+        assert!("20/week".parse::<RateLimit>().is_err());
+    }
+
+    #[test]
+    fn refill_buckets() {
+        let limit: RateLimit = "2/min".parse().unwrap();
+        let start = Instant::now();
+        let mut bucket = Bucket::full(&limit, start);
+        assert!(bucket.take(&limit, start).is_ok());
+        assert!(bucket.take(&limit, start).is_ok());
+        assert_eq!(bucket.take(&limit, start), Err(Duration::from_secs(30)));
+        assert_eq!(
+            bucket.take(&limit, start + Duration::from_secs(20)),
+            Err(Duration::from_secs(10))
+        );
+        assert!(bucket.take(&limit, start + Duration::from_secs(30)).is_ok());
+        assert!(!bucket.is_full(&limit, start + Duration::from_secs(30)));
+        assert!(bucket.is_full(&limit, start + Duration::from_secs(90)));
+    }
+
+    #[tokio::test]
+    async fn answer_429_with_retry_after() {
+        let limiter = RateLimiter::new(
+            &config(BucketStore::Memory),
+            "10.0.0.0/8".parse().unwrap(),
+            &Store::lazy(),
+        );
+        let routes = limiter
+            .by_ip(RouteGroup::Registration)
+            .map(warp::reply)
+            .recover(handle_errors::return_error);
+        let request = |forwarded_for: &str| {
+            warp::test::request()
+                .remote_addr("10.0.0.1:4000".parse().unwrap())
+                .header("x-forwarded-for", forwarded_for)
+        };
+
+        assert_eq!(request("198.51.100.7").reply(&routes).await.status(), 200);
+        let response = request("198.51.100.7").reply(&routes).await;
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers()["retry-after"], "3600");
+        assert_eq!(response.headers()["ratelimit-limit"], "1");
+        assert_eq!(response.headers()["ratelimit-remaining"], "0");
+        // Other clients behind the proxy have their own bucket.
+        assert_eq!(request("198.51.100.8").reply(&routes).await.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn forget_least_recently_used_buckets() {
+        let limiter = RateLimiter {
+            buckets: Arc::new(Mutex::new(LruCache::new(2))),
+            ..RateLimiter::new(
+                &config(BucketStore::Memory),
+                TrustedProxies::default(),
+                &Store::lazy(),
+            )
+        };
+        let now = Instant::now();
+        let take = |client: &str| {
+            limiter
+                .take(RouteGroup::Registration, client.to_string(), now)
+                .is_ok()
+        };
+
+        assert!(take("ip:a"));
+        assert!(take("ip:b"));
+        assert!(!take("ip:a"));
+        // `b` is the least recently used, `a` keeps its empty bucket.
+        assert!(take("ip:c"));
+        assert!(!take("ip:a"));
+        assert!(take("ip:b"));
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn share_the_unknown_client_bucket() {
+        let limiter = RateLimiter::new(
+            &config(BucketStore::Memory),
+            "10.0.0.0/8".parse().unwrap(),
+            &Store::lazy(),
+        );
+        assert_eq!(limiter.client_key(None, None), "ip:unknown");
+        assert_eq!(
+            limiter.client_key(None, Some("198.51.100.7")),
+            "ip:198.51.100.7"
+        );
+        assert_eq!(
+            limiter.client_key(Some("10.0.0.1:4000".parse().unwrap()), None),
+            "ip:10.0.0.1"
+        );
+    }
+
+    #[tokio::test]
+    async fn skip_disabled_limits() {
+        let limiter = RateLimiter::new(
+            &config(BucketStore::Memory),
+            TrustedProxies::default(),
+            &Store::lazy(),
+        );
+        for _ in 0..5 {
+            assert!(limiter
+                .check(RouteGroup::Answers, "account:1".to_string())
+                .await
+                .is_ok());
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL, run with --ignored"]
+    async fn share_buckets_in_postgres() {
+        let store = Store::test_database().await;
+        // Two instances sharing the database.
+        let first = RateLimiter::new(
+            &config(BucketStore::Postgres),
+            TrustedProxies::default(),
+            &store,
+        );
+        let second = RateLimiter::new(
+            &config(BucketStore::Postgres),
+            TrustedProxies::default(),
+            &store,
+        );
+        let client = format!("account:{}", uuid::Uuid::new_v4());
+
+        assert!(first
+            .check(RouteGroup::Questions, client.clone())
+            .await
+            .is_ok());
+        assert!(second
+            .check(RouteGroup::Questions, client.clone())
+            .await
+            .is_ok());
+        let rejection = first
+            .check(RouteGroup::Questions, client)
+            .await
+            .unwrap_err();
+        match rejection.find::<CustomError>() {
+            Some(CustomError::RateLimited(exceeded)) => {
+                assert_eq!(exceeded.limit, 2);
+                assert!((29..=30).contains(&exceeded.retry_after_secs));
+            }
+            other => panic!("expected RateLimited, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::types::{answer::Answer, question::Question};
+
+/// The anonymous reads kept by the cache.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ReadKey {
+    /// A page of `get_questions`.
+    Questions { limit: Option<u32>, offset: u32 },
+    /// A page of `get_question_answers`.
+    Answers {
+        question_id: i32,
+        limit: Option<u32>,
+        offset: u32,
+    },
+}
+
+/// The result of a cached read.
+#[derive(Debug, Clone)]
+pub enum CachedRead {
+    Questions(Vec<Question>),
+    Answers(Vec<Answer>),
+}
+
+/// Counters of the cache, as shown on `/cache/stats`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+struct Entry {
+    value: CachedRead,
+    inserted: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<ReadKey, Entry>,
+    /// Incremented on every access, to find the least recently used entry.
+    clock: u64,
+    /// Incremented on every write to the database.
+    generation: u64,
+}
+
+/// In-process LRU cache of anonymous reads, where entries also expire
+/// after a TTL. Writes clear it entirely. Cheap to clone, clones share
+/// the entries and counters.
+#[derive(Clone)]
+pub struct ReadCache {
+    entries: Arc<Mutex<Entries>>,
+    capacity: usize,
+    ttl: Duration,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl std::fmt::Debug for ReadCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReadCache")
+            .field("capacity", &self.capacity)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl ReadCache {
+    /// Creates a cache keeping at most `capacity` reads for `ttl`.
+    pub fn new(capacity: usize, ttl: Duration) -> ReadCache {
+        ReadCache {
+            entries: Arc::new(Mutex::new(Entries::default())),
+            capacity,
+            ttl,
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        self.entries.lock().expect("read cache poisoned")
+    }
+
+    /// Returns a fresh cached read, counting a hit or a miss.
+    pub fn get(&self, key: &ReadKey) -> Option<CachedRead> {
+        let mut entries = self.lock();
+        entries.clock += 1;
+        let clock = entries.clock;
+
+        let found = match entries.map.get_mut(key) {
+            Some(entry) if entry.inserted.elapsed() < self.ttl => {
+                entry.last_used = clock;
+                Some(entry.value.clone())
+            }
+            Some(_) => {
+                entries.map.remove(key);
+                None
+            }
+            None => None,
+        };
+
+        match found {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        found
+    }
+
+    /// The current generation, to be read before querying the database.
+    pub fn generation(&self) -> u64 {
+        self.lock().generation
+    }
+
+    /// Keeps a read made at `generation`. Reads which raced with a write
+    /// are dropped, as they may miss its changes.
+    pub fn insert(&self, key: ReadKey, value: CachedRead, generation: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.lock();
+        if entries.generation != generation {
+            return;
+        }
+
+        if entries.map.len() >= self.capacity && !entries.map.contains_key(&key) {
+            let ttl = self.ttl;
+            entries.map.retain(|_, entry| entry.inserted.elapsed() < ttl);
+        }
+        if entries.map.len() >= self.capacity && !entries.map.contains_key(&key) {
+            let oldest = entries
+                .map
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.map.remove(&oldest);
+            }
+        }
+
+        entries.clock += 1;
+        let last_used = entries.clock;
+        entries.map.insert(
+            key,
+            Entry {
+                value,
+                inserted: Instant::now(),
+                last_used,
+            },
+        );
+    }
+
+    /// Forgets every read, called after each write.
+    pub fn invalidate(&self) {
+        let mut entries = self.lock();
+        entries.generation += 1;
+        entries.map.clear();
+    }
+
+    /// The hit and miss counters and the number of entries.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.lock().map.len(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod read_cache_tests {
+    use super::*;
+
+    fn key(offset: u32) -> ReadKey {
+        ReadKey::Questions {
+            limit: Some(10),
+            offset,
+        }
+    }
+
+    fn is_cached(cache: &ReadCache, offset: u32) -> bool {
+        cache.get(&key(offset)).is_some()
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let cache = ReadCache::new(2, Duration::from_secs(60));
+        let generation = cache.generation();
+        cache.insert(key(0), CachedRead::Questions(vec![]), generation);
+        cache.insert(key(10), CachedRead::Questions(vec![]), generation);
+        assert!(is_cached(&cache, 0));
+        cache.insert(key(20), CachedRead::Questions(vec![]), generation);
+
+        assert!(is_cached(&cache, 0));
+        assert!(!is_cached(&cache, 10));
+        assert!(is_cached(&cache, 20));
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 3,
+                misses: 1,
+                entries: 2,
+                capacity: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn expire_after_ttl() {
+        let cache = ReadCache::new(2, Duration::from_millis(0));
+        cache.insert(key(0), CachedRead::Questions(vec![]), cache.generation());
+        assert!(!is_cached(&cache, 0));
+    }
+
+    #[test]
+    fn writes_invalidate() {
+        let cache = ReadCache::new(2, Duration::from_secs(60));
+        let generation = cache.generation();
+        cache.insert(key(0), CachedRead::Questions(vec![]), generation);
+        cache.invalidate();
+        assert!(!is_cached(&cache, 0));
+
+        // A read started before the write is not kept.
+        cache.insert(key(0), CachedRead::Questions(vec![]), generation);
+        assert!(!is_cached(&cache, 0));
+    }
+}
@@ -0,0 +1,129 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::time::Instant;
+use tracing::field::display;
+use warp::http::{header::HeaderValue, Method};
+use warp::hyper::body::HttpBody;
+use warp::{reply::Response, Filter, Reply};
+
+use crate::metrics;
+
+/// Header carrying the id of a request, both ways.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request id taken from a client, longer ones are replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Identifies a request in the logs and traces, and in its response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// Keeps the id a client or proxy sent when it is printable ASCII of
+    /// a sensible length, otherwise generates one.
+    pub fn new(header: Option<&str>) -> RequestId {
+        match header.map(str::trim) {
+            Some(id)
+                if !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID_LEN
+                    && id.bytes().all(|byte| byte.is_ascii_graphic()) =>
+            {
+                RequestId(id.to_string())
+            }
+            _ => RequestId(uuid::Uuid::new_v4().to_string()),
+        }
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// What the access log needs to know of a request once it is answered.
+#[derive(Debug)]
+pub struct Request {
+    id: RequestId,
+    method: Method,
+    path: String,
+    started: Instant,
+}
+
+/// Starts a request: takes or generates its id, and records it on the
+/// request span, so every log line of the request carries it.
+pub fn start() -> impl Filter<Extract = (Request,), Error = Infallible> + Clone {
+    warp::header::optional::<String>(REQUEST_ID_HEADER)
+        .or(warp::any().map(|| None))
+        .unify()
+        .and(warp::method())
+        .and(warp::path::full())
+        .map(
+            |header: Option<String>, method: Method, path: warp::path::FullPath| {
+                let id = RequestId::new(header.as_deref());
+                tracing::Span::current().record("request_id", display(&id));
+                Request {
+                    id,
+                    method,
+                    path: path.as_str().to_string(),
+                    started: Instant::now(),
+                }
+            },
+        )
+}
+
+/// Writes the access log line of an answered request, and returns its id
+/// in the response.
+pub fn finish(request: Request, reply: impl Reply) -> Response {
+    let mut response = reply.into_response();
+    let size = response.body().size_hint();
+    tracing::info!(
+        target: "web::access",
+        method = %request.method,
+        route = metrics::route(&request.path),
+        path = %request.path,
+        status = response.status().as_u16(),
+        latency_ms = request.started.elapsed().as_secs_f64() * 1000.0,
+        bytes = size.exact().unwrap_or_else(|| size.lower()),
+        "request answered"
+    );
+    if let Ok(id) = HeaderValue::from_str(&request.id.0) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, id);
+    }
+    response
+}
+
+#[cfg(test)]
+mod request_tests {
+    use super::*;
+
+    fn routes() -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+        start()
+            .and(warp::path("ping").map(|| "pong"))
+            .map(finish)
+    }
+
+    #[test]
+    fn keep_sensible_ids() {
+        assert_eq!(RequestId::new(Some("abc-123")).to_string(), "abc-123");
+        let generated = RequestId::new(None).to_string();
+        assert!(uuid::Uuid::parse_str(&generated).is_ok());
+        for bad in ["", "two words", "héllo", &"x".repeat(129)] {
+            assert_ne!(RequestId::new(Some(bad)).to_string(), bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn return_request_id() {
+        let response = warp::test::request()
+            .path("/ping")
+            .header(REQUEST_ID_HEADER, "from-proxy")
+            .reply(&routes())
+            .await;
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "from-proxy");
+
+        let response = warp::test::request().path("/ping").reply(&routes()).await;
+        let id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(id).is_ok());
+    }
+}
@@ -0,0 +1,424 @@
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use warp::http::header::{self, HeaderName, HeaderValue};
+use warp::http::{Method, Uri};
+use warp::reply::Response;
+
+use handle_errors::CustomError;
+
+use crate::request;
+
+/// Response headers scripts of other origins may read.
+const EXPOSED_HEADERS: [&str; 8] = [
+    "location",
+    "etag",
+    "last-modified",
+    request::REQUEST_ID_HEADER,
+    "retry-after",
+    "ratelimit-limit",
+    "ratelimit-remaining",
+    "ratelimit-reset",
+];
+
+/// Which browser origins may call the API, the `[cors]` section of the
+/// configuration file.
+#[derive(clap::Args, Serialize, Clone, Debug, PartialEq)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API, comma separated, e.g.
+    /// `https://qa.example.com`, or `*` for any origin
+    #[clap(
+        name = "cors-origins",
+        long = "cors-origins",
+        env = "CORS_ORIGINS",
+        default_value = "*"
+    )]
+    pub origins: CorsOrigins,
+    /// Request headers browsers may send, comma separated
+    #[clap(
+        name = "cors-headers",
+        long = "cors-headers",
+        env = "CORS_HEADERS",
+        default_value = "authorization,content-type,if-match,if-none-match,if-modified-since,x-read-primary,x-request-id"
+    )]
+    pub headers: List<HeaderName>,
+    /// Methods browsers may use, comma separated
+    #[clap(
+        name = "cors-methods",
+        long = "cors-methods",
+        env = "CORS_METHODS",
+        default_value = "GET,POST,PUT,PATCH,DELETE"
+    )]
+    pub methods: List<Method>,
+    /// Seconds browsers may cache the answer to a preflight request
+    #[clap(
+        name = "cors-max-age-secs",
+        long = "cors-max-age-secs",
+        env = "CORS_MAX_AGE_SECS",
+        default_value = "600"
+    )]
+    pub max_age_secs: u64,
+}
+
+impl CorsConfig {
+    /// Checks the methods are spelled like browsers send them.
+    pub fn validate(&self) -> Result<(), CustomError> {
+        match self
+            .methods
+            .0
+            .iter()
+            .find(|method| method.as_str() != method.as_str().to_ascii_uppercase())
+        {
+            Some(method) => Err(CustomError::ConfigError(format!(
+                "CORS method {} must be upper case",
+                method
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// The CORS filter wrapping the routes.
+    pub fn filter(&self) -> warp::cors::Builder {
+        let cors = match &self.origins {
+            CorsOrigins::Any => warp::cors().allow_any_origin(),
+            CorsOrigins::Only(origins) => {
+                warp::cors().allow_origins(origins.iter().map(String::as_str))
+            }
+        };
+        cors.allow_headers(self.headers.0.clone())
+            .allow_methods(self.methods.0.clone())
+            .expose_headers(EXPOSED_HEADERS)
+            .max_age(Duration::from_secs(self.max_age_secs))
+    }
+}
+
+/// The origins of the `Access-Control-Allow-Origin` header.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CorsOrigins {
+    Any,
+    Only(Vec<String>),
+}
+
+impl FromStr for CorsOrigins {
+    type Err = String;
+
+    /// Parses `*`, or comma separated origins like `https://example.com`
+    /// or `http://localhost:8080`.
+    fn from_str(s: &str) -> Result<CorsOrigins, String> {
+        if s.trim() == "*" {
+            return Ok(CorsOrigins::Any);
+        }
+        s.split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(|origin| {
+                let uri = origin.parse::<Uri>().ok();
+                match uri
+                    .as_ref()
+                    .map(|uri| (uri.scheme_str(), uri.authority(), uri.path_and_query()))
+                {
+                    Some((Some(scheme @ ("http" | "https")), Some(authority), path))
+                        if path.is_none_or(|path| path.as_str() == "/") =>
+                    {
+                        Ok(format!("{}://{}", scheme, authority))
+                    }
+                    _ => Err(format!("invalid CORS origin {:?}", origin)),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(CorsOrigins::Only)
+    }
+}
+
+impl fmt::Display for CorsOrigins {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CorsOrigins::Any => f.write_str("*"),
+            CorsOrigins::Only(origins) => f.write_str(&origins.join(",")),
+        }
+    }
+}
+
+impl Serialize for CorsOrigins {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Comma separated values of a setting.
+#[derive(Clone, Debug, PartialEq)]
+pub struct List<T>(pub Vec<T>);
+
+impl<T> FromStr for List<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<List<T>, String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|e| format!("invalid value {:?}: {}", value, e))
+            })
+            .collect::<Result<_, _>>()
+            .map(List)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for List<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let values: Vec<String> = self.0.iter().map(T::to_string).collect();
+        f.write_str(&values.join(","))
+    }
+}
+
+impl<T: fmt::Display> Serialize for List<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Whether pages of the server may be shown in frames.
+#[derive(clap::ArgEnum, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FrameOptions {
+    /// Never.
+    Deny,
+    /// Only in pages of the same origin.
+    SameOrigin,
+    /// `X-Frame-Options` isn't sent.
+    Off,
+}
+
+/// Headers asking browsers to be careful with the responses, the
+/// `[security]` section of the configuration file.
+#[derive(clap::Args, Serialize, Clone, Debug, PartialEq)]
+pub struct SecurityHeadersConfig {
+    /// Seconds browsers must only use HTTPS for this host, 0 to not send
+    /// `Strict-Transport-Security`
+    #[clap(
+        name = "security-hsts-max-age-secs",
+        long = "security-hsts-max-age-secs",
+        env = "HSTS_MAX_AGE_SECS",
+        default_value = "31536000"
+    )]
+    pub hsts_max_age_secs: u64,
+    /// Whether HSTS also covers the subdomains of this host
+    #[clap(
+        name = "security-hsts-include-subdomains",
+        long = "security-hsts-include-subdomains",
+        env = "HSTS_INCLUDE_SUBDOMAINS",
+        default_value = "false",
+        parse(try_from_str)
+    )]
+    pub hsts_include_subdomains: bool,
+    /// `X-Frame-Options` of the responses
+    #[clap(
+        name = "security-frame-options",
+        long = "security-frame-options",
+        env = "FRAME_OPTIONS",
+        arg_enum,
+        default_value = "deny"
+    )]
+    pub frame_options: FrameOptions,
+    /// `Content-Security-Policy` of HTML responses, `off` to not send it
+    #[clap(
+        name = "security-content-security-policy",
+        long = "security-content-security-policy",
+        env = "CONTENT_SECURITY_POLICY",
+        default_value = "default-src 'none'; frame-ancestors 'none'"
+    )]
+    pub content_security_policy: String,
+}
+
+impl SecurityHeadersConfig {
+    /// Checks the policy can be sent in a header.
+    pub fn validate(&self) -> Result<(), CustomError> {
+        SecurityHeaders::new(self).map(|_| ())
+    }
+}
+
+/// The headers of `SecurityHeadersConfig`, ready to be added to responses.
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders {
+    strict_transport_security: Option<HeaderValue>,
+    frame_options: Option<HeaderValue>,
+    content_security_policy: Option<HeaderValue>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &SecurityHeadersConfig) -> Result<SecurityHeaders, CustomError> {
+        let strict_transport_security = match config.hsts_max_age_secs {
+            0 => None,
+            max_age => {
+                let mut value = format!("max-age={}", max_age);
+                if config.hsts_include_subdomains {
+                    value.push_str("; includeSubDomains");
+                }
+                HeaderValue::from_str(&value).ok()
+            }
+        };
+        let frame_options = match config.frame_options {
+            FrameOptions::Deny => Some(HeaderValue::from_static("DENY")),
+            FrameOptions::SameOrigin => Some(HeaderValue::from_static("SAMEORIGIN")),
+            FrameOptions::Off => None,
+        };
+        let content_security_policy = match config.content_security_policy.trim() {
+            "" | "off" => None,
+            policy => Some(HeaderValue::from_str(policy).map_err(|_| {
+                CustomError::ConfigError(format!("invalid content security policy {:?}", policy))
+            })?),
+        };
+
+        Ok(SecurityHeaders {
+            strict_transport_security,
+            frame_options,
+            content_security_policy,
+        })
+    }
+
+    /// Adds the headers a handler didn't set itself. The policy is only
+    /// added to HTML, the other responses are never rendered.
+    pub fn apply(&self, mut response: Response) -> Response {
+        let is_html = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/html"));
+        let headers = response.headers_mut();
+        headers
+            .entry(header::X_CONTENT_TYPE_OPTIONS)
+            .or_insert(HeaderValue::from_static("nosniff"));
+        if let Some(value) = &self.strict_transport_security {
+            headers
+                .entry(header::STRICT_TRANSPORT_SECURITY)
+                .or_insert_with(|| value.clone());
+        }
+        if let Some(value) = &self.frame_options {
+            headers
+                .entry(header::X_FRAME_OPTIONS)
+                .or_insert_with(|| value.clone());
+        }
+        if let Some(value) = self.content_security_policy.as_ref().filter(|_| is_html) {
+            headers
+                .entry(header::CONTENT_SECURITY_POLICY)
+                .or_insert_with(|| value.clone());
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod security_tests {
+    use super::*;
+    use warp::{Filter, Reply};
+
+    fn cors_config(origins: &str) -> CorsConfig {
+        CorsConfig {
+            origins: origins.parse().unwrap(),
+            headers: "authorization,content-type".parse().unwrap(),
+            methods: "GET,POST".parse().unwrap(),
+            max_age_secs: 600,
+        }
+    }
+
+    fn security_config() -> SecurityHeadersConfig {
+        SecurityHeadersConfig {
+            hsts_max_age_secs: 31536000,
+            hsts_include_subdomains: false,
+            frame_options: FrameOptions::Deny,
+            content_security_policy: "default-src 'none'".to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_origins() {
+        assert_eq!("*".parse::<CorsOrigins>().unwrap(), CorsOrigins::Any);
+        assert_eq!(
+            "https://qa.example.com/, http://localhost:8080"
+                .parse::<CorsOrigins>()
+                .unwrap(),
+            CorsOrigins::Only(vec![
+                "https://qa.example.com".to_string(),
+                "http://localhost:8080".to_string(),
+            ])
+        );
+        for bad in [
+            "qa.example.com",
+            "ftp://qa.example.com",
+            "https://qa.example.com/app",
+        ] {
+            assert!(bad.parse::<CorsOrigins>().is_err());
+        }
+        assert!("content type".parse::<List<HeaderName>>().is_err());
+        let lower_case = CorsConfig {
+            methods: "get".parse().unwrap(),
+            ..cors_config("*")
+        };
+        assert!(lower_case.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn allow_configured_origins() {
+        let routes = warp::any()
+            .map(warp::reply)
+            .with(cors_config("https://qa.example.com").filter());
+        let preflight = |origin: &'static str| {
+            warp::test::request()
+                .method("OPTIONS")
+                .header("origin", origin)
+                .header("access-control-request-method", "POST")
+                .header("access-control-request-headers", "authorization")
+        };
+
+        let response = preflight("https://qa.example.com").reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()["access-control-allow-origin"],
+            "https://qa.example.com"
+        );
+        assert!(response.headers()["access-control-allow-headers"]
+            .to_str()
+            .unwrap()
+            .contains("authorization"));
+        assert_eq!(response.headers()["access-control-max-age"], "600");
+
+        let rejected = preflight("https://evil.example.com").filter(&routes).await;
+        assert!(rejected.is_err());
+    }
+
+    #[test]
+    fn add_security_headers() {
+        let headers = SecurityHeaders::new(&security_config()).unwrap();
+        let response = headers.apply(warp::reply::json(&"ok").into_response());
+        assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+        assert_eq!(
+            response.headers()["strict-transport-security"],
+            "max-age=31536000"
+        );
+        assert_eq!(response.headers()["x-frame-options"], "DENY");
+        assert!(!response.headers().contains_key("content-security-policy"));
+
+        let response = headers.apply(warp::reply::html("<p>hi</p>").into_response());
+        assert_eq!(
+            response.headers()["content-security-policy"],
+            "default-src 'none'"
+        );
+
+        let headers = SecurityHeaders::new(&SecurityHeadersConfig {
+            hsts_max_age_secs: 0,
+            frame_options: FrameOptions::Off,
+            ..security_config()
+        })
+        .unwrap();
+        let response = headers.apply(warp::reply().into_response());
+        assert!(!response.headers().contains_key("strict-transport-security"));
+        assert!(!response.headers().contains_key("x-frame-options"));
+    }
+}
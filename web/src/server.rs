@@ -0,0 +1,503 @@
+use ipnet::IpNet;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UnixListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio_stream::wrappers::UnixListenerStream;
+use warp::{Filter, Reply};
+
+use handle_errors::CustomError;
+
+/// Where the server listens: a TCP address, with or without TLS, or a
+/// Unix domain socket behind a local reverse proxy.
+#[derive(clap::Args, Serialize, Clone, Debug, PartialEq)]
+pub struct ListenConfig {
+    /// IPv4 or IPv6 address the server binds, e.g. 0.0.0.0 or :: to be
+    /// reachable from other hosts
+    #[clap(long, env = "BIND_ADDRESS", default_value = "127.0.0.1")]
+    pub bind_address: IpAddr,
+    /// PEM certificate chain, serves HTTPS together with `--tls-key`
+    #[clap(long, env = "TLS_CERT")]
+    pub tls_cert: Option<String>,
+    /// PEM private key of the certificate
+    #[clap(long, env = "TLS_KEY")]
+    pub tls_key: Option<String>,
+    /// Path of a Unix domain socket to listen on instead of TCP
+    #[clap(long, env = "UNIX_SOCKET")]
+    pub unix_socket: Option<String>,
+    /// Seconds in-flight requests get to finish after SIGTERM or SIGINT,
+    /// before their connections are dropped
+    #[clap(long, env = "DRAIN_TIMEOUT_SECS", default_value = "30")]
+    pub drain_timeout_secs: u64,
+    /// Seconds new connections are still accepted after SIGTERM or SIGINT,
+    /// while /readyz already fails, so load balancers stop sending requests
+    /// before the listener closes
+    #[clap(long, env = "READINESS_GRACE_SECS", default_value = "5")]
+    pub readiness_grace_secs: u64,
+    /// Reverse proxies whose `X-Forwarded-For` tells the client address,
+    /// comma separated addresses or networks, e.g. `10.0.0.0/8,::1`
+    #[clap(long, env = "TRUSTED_PROXIES", default_value = "")]
+    pub trusted_proxies: TrustedProxies,
+}
+
+/// Addresses and networks of trusted reverse proxies.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|network| network.contains(&ip))
+    }
+
+    /// The address of the client, seen as `peer`, when the request went
+    /// through proxies which appended to `forwarded_for`. The proxies are
+    /// walked back from the peer, the first untrusted address is the
+    /// client. Requests on a Unix socket, without peer, come from a local
+    /// proxy.
+    pub fn client_ip(&self, peer: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        if let Some(peer) = peer {
+            if !self.contains(peer) {
+                return Some(peer);
+            }
+        }
+        let hops: Vec<IpAddr> = forwarded_for
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|hop| hop.trim().parse().ok())
+            .collect();
+        hops.iter()
+            .rev()
+            .find(|hop| !self.contains(**hop))
+            .or_else(|| hops.first())
+            .copied()
+            .or(peer)
+    }
+}
+
+impl FromStr for TrustedProxies {
+    type Err = String;
+
+    /// Parses comma separated addresses, e.g. `10.0.0.1`, and networks,
+    /// e.g. `10.0.0.0/8`.
+    fn from_str(s: &str) -> Result<TrustedProxies, String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| {
+                proxy
+                    .parse::<IpNet>()
+                    .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("invalid proxy address {:?}", proxy))
+            })
+            .collect::<Result<_, _>>()
+            .map(TrustedProxies)
+    }
+}
+
+impl fmt::Display for TrustedProxies {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let proxies: Vec<String> = self.0.iter().map(IpNet::to_string).collect();
+        f.write_str(&proxies.join(","))
+    }
+}
+
+impl Serialize for TrustedProxies {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Tells the server and background tasks to stop. Cheap to clone, clones
+/// share the state.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    draining: Arc<watch::Sender<bool>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (draining, _) = watch::channel(false);
+        Shutdown {
+            draining: Arc::new(draining),
+        }
+    }
+
+    /// Whether the server is stopping, readiness checks fail from then on.
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Fails readiness checks, then stops accepting connections once the
+    /// readiness grace period is over and lets in-flight requests finish.
+    pub fn start_draining(&self) {
+        self.draining.send_replace(true);
+    }
+
+    /// Resolves once draining started.
+    pub async fn draining(&self) {
+        let mut draining = self.draining.subscribe();
+        while !*draining.borrow_and_update() {
+            if draining.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Starts draining on the first SIGTERM or SIGINT.
+    pub async fn on_signal(self) -> Result<(), CustomError> {
+        let mut terminate = signal(SignalKind::terminate()).map_err(|e| {
+            CustomError::ConfigError(format!("cannot listen for SIGTERM: {}", e))
+        })?;
+        tokio::select! {
+            _ = terminate.recv() => tracing::info!("SIGTERM received, draining"),
+            _ = tokio::signal::ctrl_c() => tracing::info!("SIGINT received, draining"),
+        }
+        self.start_draining();
+        Ok(())
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Shutdown {
+        Shutdown::new()
+    }
+}
+
+impl ListenConfig {
+    /// Checks TLS has both a certificate and a key, and isn't asked
+    /// together with a Unix socket, where the proxy terminates TLS.
+    pub fn validate(&self) -> Result<(), CustomError> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(_), None) | (None, Some(_)) => {
+                return Err(CustomError::ConfigError(
+                    "TLS needs both a certificate and a key".to_string(),
+                ))
+            }
+            (Some(_), Some(_)) if self.unix_socket.is_some() => {
+                return Err(CustomError::ConfigError(
+                    "TLS isn't available on a Unix socket".to_string(),
+                ))
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Where the server can be reached, for logs.
+    pub fn describe(&self, port: u16) -> String {
+        match (&self.unix_socket, &self.tls_cert) {
+            (Some(path), _) => format!("unix:{}", path),
+            (None, Some(_)) => format!("https://{}", SocketAddr::new(self.bind_address, port)),
+            (None, None) => format!("http://{}", SocketAddr::new(self.bind_address, port)),
+        }
+    }
+
+    /// Serves the routes until `shutdown` starts draining and the readiness
+    /// grace period is over, then waits for in-flight requests for at most
+    /// the drain timeout.
+    pub async fn serve<F>(
+        &self,
+        port: u16,
+        routes: F,
+        shutdown: Shutdown,
+    ) -> Result<(), CustomError>
+    where
+        F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+        F::Extract: Reply,
+    {
+        let address = SocketAddr::new(self.bind_address, port);
+        let stop = {
+            let shutdown = shutdown.clone();
+            let grace = self.readiness_grace_secs;
+            async move {
+                shutdown.draining().await;
+                // Load balancers only stop sending requests once they saw
+                // /readyz fail, keep serving the ones still coming.
+                tracing::info!("not ready, still accepting connections for {}s", grace);
+                tokio::time::sleep(Duration::from_secs(grace)).await;
+                tracing::info!("no longer accepting connections");
+            }
+        };
+        let bind_error = |e: warp::Error| {
+            CustomError::ConfigError(format!("cannot bind {}: {}", address, e))
+        };
+
+        match (&self.unix_socket, &self.tls_cert, &self.tls_key) {
+            (Some(path), _, _) => {
+                let listener = bind_unix_socket(path)?;
+                let server = warp::serve(routes).serve_incoming_with_graceful_shutdown(
+                    UnixListenerStream::new(listener),
+                    stop,
+                );
+                self.drain(server, &shutdown).await;
+            }
+            (None, Some(cert), Some(key)) => {
+                let (_, server) = warp::serve(routes)
+                    .tls()
+                    .cert_path(cert)
+                    .key_path(key)
+                    .try_bind_with_graceful_shutdown(address, stop)
+                    .map_err(bind_error)?;
+                self.drain(server, &shutdown).await;
+            }
+            _ => {
+                let (_, server) = warp::serve(routes)
+                    .try_bind_with_graceful_shutdown(address, stop)
+                    .map_err(bind_error)?;
+                self.drain(server, &shutdown).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the server, giving up on its connections once the listener was
+    /// closed for longer than the drain timeout.
+    async fn drain(&self, server: impl Future<Output = ()>, shutdown: &Shutdown) {
+        let timeout = Duration::from_secs(self.readiness_grace_secs + self.drain_timeout_secs);
+        tokio::select! {
+            _ = server => tracing::info!("all connections drained"),
+            _ = async {
+                shutdown.draining().await;
+                tokio::time::sleep(timeout).await;
+            } => tracing::warn!(
+                "requests still running after {}s, dropping their connections",
+                self.drain_timeout_secs
+            ),
+        }
+    }
+}
+
+/// Binds a Unix socket, replacing the socket a previous run left behind.
+/// Other files at that path are kept and make binding fail.
+fn bind_unix_socket(path: &str) -> Result<UnixListener, CustomError> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    UnixListener::bind(Path::new(path)).map_err(|e| {
+        CustomError::ConfigError(format!("cannot listen on {}: {}", path, e))
+    })
+}
+
+#[cfg(test)]
+mod server_tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    fn listen_config() -> ListenConfig {
+        ListenConfig {
+            bind_address: "127.0.0.1".parse().unwrap(),
+            tls_cert: None,
+            tls_key: None,
+            unix_socket: None,
+            drain_timeout_secs: 30,
+            readiness_grace_secs: 0,
+            trusted_proxies: TrustedProxies::default(),
+        }
+    }
+
+    fn socket_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("web-{}.sock", uuid::Uuid::new_v4()))
+    }
+
+    async fn connect(path: &Path) -> UnixStream {
+        loop {
+            match UnixStream::connect(path).await {
+                Ok(stream) => return stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    }
+
+    #[test]
+    fn refuse_incomplete_tls() {
+        assert!(listen_config().validate().is_ok());
+        let cert_only = ListenConfig {
+            tls_cert: Some("cert.pem".to_string()),
+            ..listen_config()
+        };
+        assert!(cert_only.validate().is_err());
+        let on_socket = ListenConfig {
+            tls_key: Some("key.pem".to_string()),
+            unix_socket: Some("web.sock".to_string()),
+            ..cert_only
+        };
+        assert!(on_socket.validate().is_err());
+    }
+
+    #[test]
+    fn describe_addresses() {
+        let v6 = ListenConfig {
+            bind_address: "::".parse().unwrap(),
+            ..listen_config()
+        };
+        assert_eq!(v6.describe(3000), "http://[::]:3000");
+        let socket = ListenConfig {
+            unix_socket: Some("/run/web.sock".to_string()),
+            ..listen_config()
+        };
+        assert_eq!(socket.describe(3000), "unix:/run/web.sock");
+    }
+
+    #[test]
+    fn find_client_behind_proxies() {
+        let proxies: TrustedProxies = "10.0.0.0/8, ::1".parse().unwrap();
+        assert_eq!(proxies.to_string(), "10.0.0.0/8,::1/128");
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+
+        // Untrusted peers can't pretend to be someone else.
+        assert_eq!(proxies.client_ip(ip("203.0.113.9"), Some("1.2.3.4")), ip("203.0.113.9"));
+        // The address the first trusted proxy saw wins over spoofed ones.
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.2"), Some("6.6.6.6, 198.51.100.7, 10.0.0.1")),
+            ip("198.51.100.7")
+        );
+        assert_eq!(proxies.client_ip(ip("10.0.0.2"), None), ip("10.0.0.2"));
+        assert_eq!(proxies.client_ip(None, Some("198.51.100.7")), ip("198.51.100.7"));
+        assert_eq!(proxies.client_ip(None, None), None);
+        assert!("10.0.0.0/33".parse::<TrustedProxies>().is_err());
+    }
+
+    #[tokio::test]
+    async fn serve_on_unix_socket() {
+        let path = socket_path();
+        let config = ListenConfig {
+            unix_socket: Some(path.to_string_lossy().into_owned()),
+            ..listen_config()
+        };
+        let shutdown = Shutdown::new();
+        let routes = warp::path("ping").map(|| "pong");
+        let server = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move { config.serve(0, routes, shutdown).await })
+        };
+
+        let mut stream = connect(&path).await;
+        stream
+            .write_all(b"GET /ping HTTP/1.1\r\nHost: local\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("pong"));
+
+        assert!(!shutdown.is_draining());
+        shutdown.start_draining();
+        assert!(shutdown.is_draining());
+        server.await.unwrap().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn finish_requests_while_draining() {
+        let path = socket_path();
+        let config = ListenConfig {
+            unix_socket: Some(path.to_string_lossy().into_owned()),
+            ..listen_config()
+        };
+        let shutdown = Shutdown::new();
+        let routes = warp::path("slow").and_then(|| async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok::<_, warp::Rejection>("done")
+        });
+        let server = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move { config.serve(0, routes, shutdown).await })
+        };
+
+        let mut stream = connect(&path).await;
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: local\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.start_draining();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("done"));
+        server.await.unwrap().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn drop_requests_after_drain_timeout() {
+        let path = socket_path();
+        let config = ListenConfig {
+            unix_socket: Some(path.to_string_lossy().into_owned()),
+            drain_timeout_secs: 0,
+            ..listen_config()
+        };
+        let shutdown = Shutdown::new();
+        let routes = warp::path("stuck").and_then(|| async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok::<_, warp::Rejection>("done")
+        });
+        let server = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move { config.serve(0, routes, shutdown).await })
+        };
+
+        let mut stream = connect(&path).await;
+        stream
+            .write_all(b"GET /stuck HTTP/1.1\r\nHost: local\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.start_draining();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server stopped after the drain timeout")
+            .unwrap()
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn accept_connections_during_readiness_grace() {
+        let path = socket_path();
+        let config = ListenConfig {
+            unix_socket: Some(path.to_string_lossy().into_owned()),
+            readiness_grace_secs: 1,
+            ..listen_config()
+        };
+        let shutdown = Shutdown::new();
+        let routes = warp::path("ping").map(|| "pong");
+        let server = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move { config.serve(0, routes, shutdown).await })
+        };
+        drop(connect(&path).await);
+
+        shutdown.start_draining();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /ping HTTP/1.1\r\nHost: local\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("pong"));
+        assert!(!server.is_finished());
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server stopped after the readiness grace period")
+            .unwrap()
+            .unwrap();
+        assert!(UnixStream::connect(&path).await.is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
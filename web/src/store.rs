@@ -1,60 +1,401 @@
-use sqlx::postgres::{PgPool, PgPoolOptions, PgRow};
-use sqlx::Row;
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::{PgConnectOptions, PgConnection, PgPool, PgPoolOptions, PgRow};
+use serde::Serialize;
+use sqlx::{Postgres, Row};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tracing::instrument;
 
 use handle_errors::CustomError;
 
+use chrono::{DateTime, Utc};
+
+use crate::metrics;
+use crate::read_cache::{CacheStats, CachedRead, ReadCache, ReadKey};
 use crate::types::{
-    account::{Account, AccountId},
-    answer::{Answer, AnswerId, NewAnswer},
-    question::{NewQuestion, Question, QuestionId},
+    account::{
+        Account, AccountDetails, AccountId, ActiveSession, Author, ClientInfo, Session,
+        SessionId,
+    },
+    api_token::{ApiToken, ApiTokenId, Scope},
+    answer::{Answer, AnswerId, AnswerUpdate, NewAnswer},
+    question::{NewQuestion, Question, QuestionId, QuestionPatch},
 };
+
+/// A connection taken from a pool. The acquire future and the connection
+/// are boxed, they would make the futures of the queries holding them too
+/// large for the stack of the debug builds.
+type Connection = Box<PoolConnection<Postgres>>;
+
+/// Columns read by `question_from_row`, for a question aliased `q` joined
+/// with its author aliased `a`.
+const QUESTION_COLUMNS: &str = "q.id, q.title, q.content, q.tags,
+    q.version, q.created_on, q.updated_on, a.id AS author_id,
+    COALESCE(a.display_name, split_part(a.email, '@', 1)) AS author_name,
+    (SELECT COUNT(*) FROM answers WHERE corresponding_question = q.id)
+        AS answer_count,
+    (SELECT MAX(created_on) FROM answers WHERE corresponding_question = q.id)
+        AS last_answer_at";
+
+/// Columns read by `answer_from_row`, for an answer aliased `an` joined
+/// with its author aliased `a`.
+const ANSWER_COLUMNS: &str = "an.id, an.content, an.corresponding_question,
+    an.version, an.created_on, an.updated_on, a.id AS author_id,
+    COALESCE(a.display_name, split_part(a.email, '@', 1)) AS author_name";
+/// Settings of the database connection pool.
+#[derive(clap::Args, Serialize, Debug, Clone, PartialEq)]
+pub struct PoolConfig {
+    /// Connections kept open even when idle
+    #[clap(
+        name = "db-min-connections",
+        long = "db-min-connections",
+        env = "DB_MIN_CONNECTIONS",
+        default_value = "0"
+    )]
+    pub min_connections: u32,
+    /// Maximum number of open connections
+    #[clap(
+        name = "db-max-connections",
+        long = "db-max-connections",
+        env = "DB_MAX_CONNECTIONS",
+        default_value = "5"
+    )]
+    pub max_connections: u32,
+    /// Seconds to wait for a free connection before failing a request
+    #[clap(
+        name = "db-acquire-timeout-secs",
+        long = "db-acquire-timeout-secs",
+        env = "DB_ACQUIRE_TIMEOUT_SECS",
+        default_value = "30"
+    )]
+    pub acquire_timeout_secs: u64,
+    /// Seconds after which idle connections are closed, 0 keeps them
+    #[clap(
+        name = "db-idle-timeout-secs",
+        long = "db-idle-timeout-secs",
+        env = "DB_IDLE_TIMEOUT_SECS",
+        default_value = "600"
+    )]
+    pub idle_timeout_secs: u64,
+    /// Milliseconds after which Postgres cancels a statement, 0 for none
+    #[clap(
+        name = "db-statement-timeout-ms",
+        long = "db-statement-timeout-ms",
+        env = "DB_STATEMENT_TIMEOUT_MS",
+        default_value = "0"
+    )]
+    pub statement_timeout_ms: u64,
+}
+
+impl PoolConfig {
+    /// Checks the pool can open at least one connection and keep the
+    /// minimum open.
+    pub fn validate(&self) -> Result<(), CustomError> {
+        if self.max_connections == 0 || self.min_connections > self.max_connections {
+            return Err(CustomError::ConfigError(format!(
+                "need 0 <= min ({}) <= max ({}) database connections, max >= 1",
+                self.min_connections, self.max_connections
+            )));
+        }
+        Ok(())
+    }
+
+    /// Opens a pool of connections to the database with these settings.
+    pub async fn connect(
+        &self,
+        options: PgConnectOptions,
+    ) -> Result<PgPool, sqlx::Error> {
+        self.pool_options()
+            .connect_with(self.connect_options(options))
+            .await
+    }
+
+    /// Builds a pool connecting with the given options.
+    fn pool_options(&self) -> PgPoolOptions {
+        let idle_timeout = match self.idle_timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        PgPoolOptions::new()
+            .min_connections(self.min_connections)
+            .max_connections(self.max_connections)
+            .connect_timeout(Duration::from_secs(self.acquire_timeout_secs))
+            .idle_timeout(idle_timeout)
+    }
+
+    /// Adds the statement timeout to the options of each connection.
+    fn connect_options(&self, options: PgConnectOptions) -> PgConnectOptions {
+        match self.statement_timeout_ms {
+            0 => options,
+            ms => options.options([("statement_timeout", ms.to_string())]),
+        }
+    }
+}
+
 /// The Store object represents the connection and interaction 
 /// with a PostgreSQL database.
 #[derive(Debug, Clone)]
 pub struct Store {
     /// Connection to the PostgreSQL database.
     pub connection: PgPool, 
+    /// Optional connection to a read replica, used by the list and login
+    /// reads. Writes always go to `connection`.
+    pub replica: Option<PgPool>,
+    /// Whether reads skip the replica and the read cache, so a request
+    /// sees its own writes.
+    pub read_primary: bool,
+    /// Optional cache of anonymous question and answer reads.
+    pub read_cache: Option<ReadCache>,
 }
 
 impl Store {
-    /// This function creates a new Store object from the given database URL.
+    /// This function creates a new Store object connecting with the given
+    /// options, see `Config::connect_options`.
     /// # Example usage
     /// ``` text
-    /// let store = store::Store::new(postgres://username@password/host:port/databse)
+    /// let store = store::Store::new(config.connect_options()?, &config.pool)
     ///             .await?;
     /// ```
-    pub async fn new(db_url: &str) -> Result<Self, sqlx::Error> {
-        tracing::warn!("{}", db_url);
-        let db_pool = PgPoolOptions::new()
-            .max_connections(5)
-            .connect(db_url)
-            .await?;
-
-        Ok(Store {
-            connection: db_pool,
+    pub async fn new(
+        options: PgConnectOptions,
+        pool: &PoolConfig,
+    ) -> Result<Self, sqlx::Error> {
+        let db_pool = pool.connect(options).await?;
+
+        Ok(Store::from_pool(db_pool))
+    }
+
+    /// A store on an open pool, reading from the primary without a cache.
+    pub fn from_pool(connection: PgPool) -> Store {
+        Store {
+            connection,
+            replica: None,
+            read_primary: false,
+            read_cache: None,
+        }
+    }
+
+    /// Serves the list and login reads from a replica.
+    pub fn with_replica(self, replica: PgPool) -> Store {
+        Store {
+            replica: Some(replica),
+            ..self
+        }
+    }
+
+    /// The same store reading from the primary only, bypassing the read
+    /// cache, for reads which must see the writes just made. Its writes
+    /// still clear the cache.
+    pub fn primary(self) -> Store {
+        Store {
+            read_primary: true,
+            ..self
+        }
+    }
+
+    /// The pool reads which may lag behind writes are sent to, and its
+    /// name in the metrics.
+    fn reader(&self) -> (&'static str, &PgPool) {
+        match &self.replica {
+            Some(replica) if !self.read_primary => ("replica", replica),
+            _ => ("primary", &self.connection),
+        }
+    }
+
+    /// Takes a connection to the primary, for writes and the reads which
+    /// must see them.
+    async fn primary_connection(&self) -> Result<Connection, CustomError> {
+        acquire("primary", &self.connection).await.map_err(|e| {
+            tracing::event!(tracing::Level::ERROR, "{:?}", e);
+            CustomError::DatabaseQueryError(e)
         })
     }
+
+    /// Takes a connection to the reader.
+    async fn reader_connection(&self) -> Result<Connection, CustomError> {
+        let (name, pool) = self.reader();
+        acquire(name, pool).await.map_err(|e| {
+            tracing::event!(tracing::Level::ERROR, "{:?}", e);
+            CustomError::DatabaseQueryError(e)
+        })
+    }
+
+    /// Runs a read of at most one row on the reader, and again on the
+    /// primary when the replica doesn't have the row: it may have been
+    /// created just before, e.g. by the request which returned its
+    /// `Location`.
+    async fn find_fresh<T, F, Fut>(&self, find: F) -> Result<Option<T>, sqlx::Error>
+    where
+        F: Fn(Connection) -> Fut,
+        Fut: Future<Output = Result<Option<T>, sqlx::Error>>,
+    {
+        let (name, pool) = self.reader();
+        match find(acquire(name, pool).await?).await {
+            Ok(None) if self.replica.is_some() && !self.read_primary => {
+                find(acquire("primary", &self.connection).await?).await
+            }
+            found => found,
+        }
+    }
+
+    /// The read cache to look cached reads up in, `None` when disabled or
+    /// when reading from the primary.
+    fn cached_reads(&self) -> Option<&ReadCache> {
+        self.read_cache.as_ref().filter(|_| !self.read_primary)
+    }
+
+    /// Checks the primary and the replica answer, and returns the
+    /// migrations of this build which the primary hasn't applied.
+    pub async fn pending_migrations(self) -> Result<Vec<i64>, CustomError> {
+        if let Some(replica) = &self.replica {
+            if let Err(e) = sqlx::query("SELECT 1").execute(replica).await {
+                tracing::event!(tracing::Level::ERROR, "{:?}", e);
+                return Err(CustomError::DatabaseQueryError(e));
+            }
+        }
+
+        match sqlx::query("SELECT version FROM _sqlx_migrations WHERE success")
+            .map(|row: PgRow| row.get::<i64, _>("version"))
+            .fetch_all(&self.connection)
+            .await
+        {
+            Ok(applied) => Ok(sqlx::migrate!()
+                .iter()
+                .filter(|migration| !migration.migration_type.is_down_migration())
+                .map(|migration| migration.version)
+                .filter(|version| !applied.contains(version))
+                .collect()),
+            Err(e) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", e);
+                Err(CustomError::DatabaseQueryError(e))
+            }
+        }
+    }
+
+    /// Closes the connections to the primary and the replica, waiting for
+    /// the queries still running.
+    pub async fn close(&self) {
+        self.connection.close().await;
+        if let Some(replica) = &self.replica {
+            replica.close().await;
+        }
+    }
+
+    /// Puts a read cache in front of the question and answer lists.
+    pub fn with_read_cache(self, read_cache: ReadCache) -> Store {
+        Store {
+            read_cache: Some(read_cache),
+            ..self
+        }
+    }
+
+    /// The counters of the read cache, `None` when it is disabled.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.read_cache.as_ref().map(ReadCache::stats)
+    }
+
+    /// Forgets the cached reads after a write.
+    fn invalidate_reads(&self) {
+        if let Some(read_cache) = &self.read_cache {
+            read_cache.invalidate();
+        }
+    }
     
     /// This function retrieves a list of questions from the database with
-    /// optional limits and offsets.
+    /// optional limits and offsets. When a viewer is given, each question
+    /// tells whether the viewer asked it.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "get_questions",
+            otel.kind = "client"
+        )
+    )]
     pub async fn get_questions(
         self,
         limit: Option<u32>,
         offset: u32,
+        viewer: Option<AccountId>,
     ) -> Result<Vec<Question>, CustomError> {
-        match sqlx::query("SELECT * from questions LIMIT $1 OFFSET $2")
+        // Only anonymous reads are cached, others depend on the viewer.
+        let key = ReadKey::Questions { limit, offset };
+        let read_cache = self.cached_reads().filter(|_| viewer.is_none());
+        if let Some(read_cache) = read_cache {
+            if let Some(CachedRead::Questions(questions)) = read_cache.get(&key) {
+                return Ok(questions);
+            }
+        }
+        let generation = read_cache.map(ReadCache::generation);
+
+        let _timer = metrics::query_timer("get_questions");
+        let mut connection = self.reader_connection().await?;
+        match sqlx::query(&format!(
+            "SELECT {}, q.account_id = $3 AS asked_by_me
+            FROM questions q JOIN accounts a ON a.id = q.account_id
+            ORDER BY q.id LIMIT $1 OFFSET $2",
+            QUESTION_COLUMNS
+        ))
             .bind(limit)
             .bind(offset)
-            .map(|row: PgRow| Question {
-                id: QuestionId(row.get("id")),
-                title: row.get("title"),
-                content: row.get("content"),
-                tags: row.get("tags"),
+            .bind(viewer.map(|viewer| viewer.0))
+            .map(question_from_row)
+            .fetch_all(&mut *connection)
+            .await
+        {
+            Ok(questions) => {
+                if let (Some(read_cache), Some(generation)) = (read_cache, generation) {
+                    read_cache.insert(
+                        key,
+                        CachedRead::Questions(questions.clone()),
+                        generation,
+                    );
+                }
+                Ok(questions)
+            }
+            Err(e) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", e);
+                Err(CustomError::DatabaseQueryError(e))
+            }
+        }
+    }
+
+    /// This function retrieves a single question, `None` when it doesn't
+    /// exist. When a viewer is given, it tells whether the viewer asked it.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "get_question",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn get_question(
+        self,
+        id: i32,
+        viewer: Option<AccountId>,
+    ) -> Result<Option<Question>, CustomError> {
+        let _timer = metrics::query_timer("get_question");
+        let query = format!(
+            "SELECT {}, q.account_id = $2 AS asked_by_me
+            FROM questions q JOIN accounts a ON a.id = q.account_id
+            WHERE q.id = $1",
+            QUESTION_COLUMNS
+        );
+        let query = &query;
+        let viewer = viewer.map(|viewer| viewer.0);
+        match self
+            .find_fresh(|mut connection| async move {
+                sqlx::query(query)
+                    .bind(id)
+                    .bind(viewer)
+                    .map(question_from_row)
+                    .fetch_optional(&mut *connection)
+                    .await
             })
-            .fetch_all(&self.connection)
             .await
         {
-            Ok(questions) => Ok(questions),
+            Ok(question) => Ok(question),
             Err(e) => {
                 tracing::event!(tracing::Level::ERROR, "{:?}", e);
                 Err(CustomError::DatabaseQueryError(e))
@@ -63,17 +404,27 @@ impl Store {
     }
 
     /// This function checks if a user is the owner of a question.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "is_question_owner",
+            otel.kind = "client"
+        )
+    )]
     pub async fn is_question_owner(
         &self,
         question_id: i32,
         account_id: &AccountId,
     ) -> Result<bool, CustomError> {
+        let _timer = metrics::query_timer("is_question_owner");
+        let mut connection = self.primary_connection().await?;
         match sqlx::query(
             "SELECT * from questions where id = $1 and account_id = $2",
         )
         .bind(question_id)
         .bind(account_id.0)
-        .fetch_optional(&self.connection)
+        .fetch_optional(&mut *connection)
         .await
         {
             Ok(question) => Ok(question.is_some()),
@@ -85,25 +436,40 @@ impl Store {
     }
 
     /// This function adds a new question to the database.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "add_question",
+            otel.kind = "client"
+        )
+    )]
     pub async fn add_question(
         self,
         new_question: NewQuestion,
         account_id: AccountId,
     ) -> Result<Question, CustomError> {
-        match sqlx::query("INSERT INTO questions (title, content, tags, account_id) VALUES ($1, $2, $3, $4) RETURNING id, title, content, tags")
+        let _timer = metrics::query_timer("add_question");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(&format!(
+            "WITH q AS (
+                INSERT INTO questions (title, content, tags, account_id)
+                VALUES ($1, $2, $3, $4) RETURNING *
+            )
+            SELECT {} FROM q JOIN accounts a ON a.id = q.account_id",
+            QUESTION_COLUMNS
+        ))
             .bind(new_question.title)
             .bind(new_question.content)
             .bind(new_question.tags)
             .bind(account_id.0)
-            .map(|row: PgRow| Question {
-			    id: QuestionId(row.get("id")),
-                title: row.get("title"),
-			    content: row.get("content"),
-                tags: row.get("tags"),
-		    })
-            .fetch_one(&self.connection)
+            .map(question_from_row)
+            .fetch_one(&mut *connection)
             .await {
-                Ok(question) => Ok(question),
+                Ok(question) => {
+                    self.invalidate_reads();
+                    Ok(question)
+                },
                 Err(error) => {
                     tracing::event!(tracing::Level::ERROR, "{:?}", error);
                     Err(CustomError::DatabaseQueryError(error))
@@ -111,33 +477,106 @@ impl Store {
             }
     }
 
-    /// This function updates an existing question in the database.
+    /// This function replaces the title, content and tags of a question.
+    /// `expected` are the versions the editor may overwrite, `None` for
+    /// any. Returns `None` when the question is at another version.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "update_question",
+            otel.kind = "client"
+        )
+    )]
     pub async fn update_question(
         self,
-        question: Question,
+        question: NewQuestion,
         id: i32,
         account_id: AccountId,
-    ) -> Result<Question, CustomError> {
-        match sqlx::query(
-            "UPDATE questions SET title = $1, content = $2, tags = $3
-        WHERE id = $4 AND account_id = $5
-        RETURNING id, title, content, tags",
-        )
+        expected: Option<Vec<i32>>,
+    ) -> Result<Option<Question>, CustomError> {
+        let _timer = metrics::query_timer("update_question");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(&format!(
+            "WITH q AS (
+                UPDATE questions
+                SET title = $1, content = $2, tags = $3,
+                    version = version + 1, updated_on = NOW()
+                WHERE id = $4 AND account_id = $5
+                    AND ($6::integer[] IS NULL OR version = ANY($6))
+                RETURNING *
+            )
+            SELECT {} FROM q JOIN accounts a ON a.id = q.account_id",
+            QUESTION_COLUMNS
+        ))
         .bind(question.title)
         .bind(question.content)
         .bind(question.tags)
         .bind(id)
         .bind(account_id.0)
-        .map(|row: PgRow| Question {
-            id: QuestionId(row.get("id")),
-            title: row.get("title"),
-            content: row.get("content"),
-            tags: row.get("tags"),
-        })
-        .fetch_one(&self.connection)
+        .bind(expected)
+        .map(question_from_row)
+        .fetch_optional(&mut *connection)
         .await
         {
-            Ok(question) => Ok(question),
+            Ok(question) => {
+                self.invalidate_reads();
+                Ok(question)
+            }
+            Err(error) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", error);
+                Err(CustomError::DatabaseQueryError(error))
+            }
+        }
+    }
+
+    /// This function changes only the given fields of a question. Versions
+    /// are checked like in `update_question`.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "patch_question",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn patch_question(
+        self,
+        patch: QuestionPatch,
+        id: i32,
+        account_id: AccountId,
+        expected: Option<Vec<i32>>,
+    ) -> Result<Option<Question>, CustomError> {
+        let _timer = metrics::query_timer("patch_question");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(&format!(
+            "WITH q AS (
+                UPDATE questions
+                SET title = COALESCE($1, title),
+                    content = COALESCE($2, content),
+                    tags = COALESCE($3, tags),
+                    version = version + 1, updated_on = NOW()
+                WHERE id = $4 AND account_id = $5
+                    AND ($6::integer[] IS NULL OR version = ANY($6))
+                RETURNING *
+            )
+            SELECT {} FROM q JOIN accounts a ON a.id = q.account_id",
+            QUESTION_COLUMNS
+        ))
+        .bind(patch.title)
+        .bind(patch.content)
+        .bind(patch.tags)
+        .bind(id)
+        .bind(account_id.0)
+        .bind(expected)
+        .map(question_from_row)
+        .fetch_optional(&mut *connection)
+        .await
+        {
+            Ok(question) => {
+                self.invalidate_reads();
+                Ok(question)
+            }
             Err(error) => {
                 tracing::event!(tracing::Level::ERROR, "{:?}", error);
                 Err(CustomError::DatabaseQueryError(error))
@@ -146,21 +585,34 @@ impl Store {
     }
 
     /// This function deletes a question from the database.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "delete_question",
+            otel.kind = "client"
+        )
+    )]
     pub async fn delete_question(
         self,
         id: i32,
         account_id: AccountId,
     ) -> Result<bool, CustomError> {
         
+        let _timer = metrics::query_timer("delete_question");
         match self.clone().delete_all_question_answers(id).await {
             Ok(_) => {
+                let mut connection = self.primary_connection().await?;
                 match sqlx::query("DELETE FROM questions WHERE id = $1 AND account_id = $2",)
                     .bind(id)
                     .bind(account_id.0)
-                    .execute(&self.connection)
+                    .execute(&mut *connection)
                     .await
                 {
-                    Ok(_) => Ok(true),
+                    Ok(_) => {
+                        self.invalidate_reads();
+                        Ok(true)
+                    }
                     Err(e) => {
                         tracing::event!(tracing::Level::ERROR, "{:?}", e);
                         Err(CustomError::DatabaseQueryError(e))
@@ -176,15 +628,24 @@ impl Store {
 
     /// This function deletes all answers associated with a specific 
     /// question from the database.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "delete_all_question_answers",
+            otel.kind = "client"
+        )
+    )]
     async fn delete_all_question_answers(
         self,
         id: i32,
     ) -> Result<bool, CustomError> {
+        let mut connection = self.primary_connection().await?;
         match sqlx::query(
             "DELETE FROM answers WHERE corresponding_question = $1",
         )
         .bind(id)
-        .execute(&self.connection)
+        .execute(&mut *connection)
         .await
         {
             Ok(_) => Ok(true),
@@ -196,42 +657,133 @@ impl Store {
     }
 
     /// This function adds a new answer to the database.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "add_answer",
+            otel.kind = "client"
+        )
+    )]
     pub async fn add_answer(
         self,
         new_answer: NewAnswer,
         account_id: AccountId,
     ) -> Result<Answer, CustomError> {
 
-        match sqlx::query(
-            "INSERT INTO answers (content, corresponding_question, account_id) VALUES ($1, $2, $3)
-            RETURNING id, content, corresponding_question",
-        )
+        let _timer = metrics::query_timer("add_answer");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(&format!(
+            "WITH an AS (
+                INSERT INTO answers (content, corresponding_question, account_id)
+                VALUES ($1, $2, $3) RETURNING *
+            )
+            SELECT {} FROM an JOIN accounts a ON a.id = an.account_id",
+            ANSWER_COLUMNS
+        ))
         .bind(new_answer.content)
         .bind(new_answer.question_id.0)
         .bind(account_id.0)
-        .map(|row: PgRow| Answer {
-            id: AnswerId(row.get("id")),
-            content: row.get("content"),
-            question_id: QuestionId(row.get("corresponding_question")),
-        })
-        .fetch_one(&self.connection)
+        .map(answer_from_row)
+        .fetch_one(&mut *connection)
         .await
         {
-            Ok(answer) => Ok(answer),
+            Ok(answer) => {
+                self.invalidate_reads();
+                Ok(answer)
+            }
             Err(error) => {
-                tracing::event!(
-                    tracing::Level::ERROR,
-                    code = error
-                        .as_database_error()
-                        .unwrap()
-                        .code()
-                        .unwrap()
-                        .parse::<i32>()
-                        .unwrap(),
-                    db_message = error.as_database_error().unwrap().message(),
-                    constraint = error.as_database_error().unwrap().constraint().unwrap()
-                );
+                if let Some(db) = error.as_database_error() {
+                    tracing::event!(
+                        tracing::Level::ERROR,
+                        ?error,
+                        code = ?db.code(),
+                        constraint = ?db.constraint()
+                    );
+                } else {
+                    tracing::event!(tracing::Level::ERROR, ?error);
+                }
+                Err(CustomError::DatabaseQueryError(error))
+            }
+        }
+    }
 
+    /// This function checks if a user is the author of an answer.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "is_answer_owner",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn is_answer_owner(
+        &self,
+        answer_id: i32,
+        account_id: &AccountId,
+    ) -> Result<bool, CustomError> {
+        let _timer = metrics::query_timer("is_answer_owner");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(
+            "SELECT id from answers where id = $1 and account_id = $2",
+        )
+        .bind(answer_id)
+        .bind(account_id.0)
+        .fetch_optional(&mut *connection)
+        .await
+        {
+            Ok(answer) => Ok(answer.is_some()),
+            Err(e) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", e);
+                Err(CustomError::DatabaseQueryError(e))
+            }
+        }
+    }
+
+    /// This function replaces the content of an answer. Versions are
+    /// checked like in `update_question`.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "update_answer",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn update_answer(
+        self,
+        answer: AnswerUpdate,
+        id: i32,
+        account_id: AccountId,
+        expected: Option<Vec<i32>>,
+    ) -> Result<Option<Answer>, CustomError> {
+        let _timer = metrics::query_timer("update_answer");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(&format!(
+            "WITH an AS (
+                UPDATE answers
+                SET content = $1, version = version + 1, updated_on = NOW()
+                WHERE id = $2 AND account_id = $3
+                    AND ($4::integer[] IS NULL OR version = ANY($4))
+                RETURNING *
+            )
+            SELECT {} FROM an JOIN accounts a ON a.id = an.account_id",
+            ANSWER_COLUMNS
+        ))
+        .bind(answer.content)
+        .bind(id)
+        .bind(account_id.0)
+        .bind(expected)
+        .map(answer_from_row)
+        .fetch_optional(&mut *connection)
+        .await
+        {
+            Ok(answer) => {
+                self.invalidate_reads();
+                Ok(answer)
+            }
+            Err(error) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", error);
                 Err(CustomError::DatabaseQueryError(error))
             }
         }
@@ -239,25 +791,96 @@ impl Store {
 
     /// This function retrieves a list of answers for a specific question 
     /// from the database with optional limits and offsets.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "get_question_answers",
+            otel.kind = "client"
+        )
+    )]
     pub async fn get_question_answers(
         self,
         limit: Option<u32>,
         offset: u32,
         question_id: i32,
     ) -> Result<Vec<Answer>, CustomError> {
-        match sqlx::query("SELECT * from answers where corresponding_question = $1 LIMIT $2 OFFSET $3")
+        let key = ReadKey::Answers {
+            question_id,
+            limit,
+            offset,
+        };
+        let read_cache = self.cached_reads();
+        if let Some(read_cache) = read_cache {
+            if let Some(CachedRead::Answers(answers)) = read_cache.get(&key) {
+                return Ok(answers);
+            }
+        }
+        let generation = read_cache.map(ReadCache::generation);
+
+        let _timer = metrics::query_timer("get_question_answers");
+        let mut connection = self.reader_connection().await?;
+        match sqlx::query(&format!(
+            "SELECT {} FROM answers an JOIN accounts a ON a.id = an.account_id
+            WHERE an.corresponding_question = $1
+            ORDER BY an.id LIMIT $2 OFFSET $3",
+            ANSWER_COLUMNS
+        ))
             .bind(question_id)
             .bind(limit)
             .bind(offset)
-            .map(|row: PgRow| Answer {
-                id: AnswerId(row.get("id")),
-                content: row.get("content"),
-                question_id: QuestionId(question_id),
+            .map(answer_from_row)
+            .fetch_all(&mut *connection)
+            .await
+        {
+            Ok(answers) => {
+                if let (Some(read_cache), Some(generation)) =
+                    (read_cache, generation)
+                {
+                    read_cache.insert(
+                        key,
+                        CachedRead::Answers(answers.clone()),
+                        generation,
+                    );
+                }
+                Ok(answers)
+            }
+            Err(e) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", e);
+                Err(CustomError::DatabaseQueryError(e))
+            }
+        }
+    }
+
+    /// This function retrieves a single answer, `None` when it doesn't
+    /// exist.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "get_answer",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn get_answer(self, id: i32) -> Result<Option<Answer>, CustomError> {
+        let _timer = metrics::query_timer("get_answer");
+        let query = format!(
+            "SELECT {} FROM answers an JOIN accounts a ON a.id = an.account_id
+            WHERE an.id = $1",
+            ANSWER_COLUMNS
+        );
+        let query = &query;
+        match self
+            .find_fresh(|mut connection| async move {
+                sqlx::query(query)
+                    .bind(id)
+                    .map(answer_from_row)
+                    .fetch_optional(&mut *connection)
+                    .await
             })
-            .fetch_all(&self.connection)
             .await
         {
-            Ok(answers) => Ok(answers),
+            Ok(answer) => Ok(answer),
             Err(e) => {
                 tracing::event!(tracing::Level::ERROR, "{:?}", e);
                 Err(CustomError::DatabaseQueryError(e))
@@ -265,64 +888,767 @@ impl Store {
         }
     }
 
-    /// This function adds a new account to the database.
+    /// This function adds a new account to the database, and returns it
+    /// without its password.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "add_account",
+            otel.kind = "client"
+        )
+    )]
     pub async fn add_account(
         self,
         account: Account,
-    ) -> Result<bool, CustomError> {
+    ) -> Result<AccountDetails, CustomError> {
+        let _timer = metrics::query_timer("add_account");
+        let mut connection = self.primary_connection().await?;
         match sqlx::query(
-            "INSERT INTO accounts (email, password) VALUES ($1, $2)",
+            "INSERT INTO accounts (email, password, display_name)
+            VALUES ($1, $2, $3)
+            RETURNING id, email,
+                COALESCE(display_name, split_part(email, '@', 1)) AS display_name",
         )
         .bind(account.email)
         .bind(account.password)
-        .execute(&self.connection)
+        .bind(account.display_name)
+        .map(|row: PgRow| AccountDetails {
+            id: AccountId(row.get("id")),
+            email: row.get("email"),
+            display_name: row.get("display_name"),
+        })
+        .fetch_one(&mut *connection)
         .await
         {
-            Ok(_) => Ok(true),
+            Ok(account) => Ok(account),
             Err(error) => {
-                tracing::event!(
-                    tracing::Level::ERROR,
-                    code = error
-                        .as_database_error()
-                        .unwrap()
-                        .code()
-                        .unwrap()
-                        .parse::<i32>()
-                        .unwrap(),
-                    db_message =
-                        error.as_database_error().unwrap().message(),
-                    constraint = error
-                        .as_database_error()
-                        .unwrap()
-                        .constraint()
-                        .unwrap()
-                );
+                if let Some(db) = error.as_database_error() {
+                    tracing::event!(
+                        tracing::Level::ERROR,
+                        ?error,
+                        code = ?db.code(),
+                        constraint = ?db.constraint()
+                    );
+                } else {
+                    tracing::event!(tracing::Level::ERROR, ?error);
+                }
                 Err(CustomError::DatabaseQueryError(error))
             }
         }
     }
 
+    /// This function retrieves the public profile of an account, `None`
+    /// when it doesn't exist.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "get_author",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn get_author(self, id: i32) -> Result<Option<Author>, CustomError> {
+        let _timer = metrics::query_timer("get_author");
+        match self
+            .find_fresh(|mut connection| async move {
+                sqlx::query(
+                    "SELECT id, COALESCE(display_name, split_part(email, '@', 1))
+                        AS display_name
+                    FROM accounts WHERE id = $1",
+                )
+                .bind(id)
+                .map(|row: PgRow| Author {
+                    id: AccountId(row.get("id")),
+                    display_name: row.get("display_name"),
+                })
+                .fetch_optional(&mut *connection)
+                .await
+            })
+            .await
+        {
+            Ok(author) => Ok(author),
+            Err(e) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", e);
+                Err(CustomError::DatabaseQueryError(e))
+            }
+        }
+    }
+
     /// This function retrieves an account from the database by its email address.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "get_account",
+            otel.kind = "client"
+        )
+    )]
     pub async fn get_account(
         self,
         email: String,
     ) -> Result<Account, CustomError> {
-        match sqlx::query("SELECT * from accounts where email = $1")
-            .bind(email)
-            .map(|row: PgRow| Account {
-                id: Some(AccountId(row.get("id"))),
-                email: row.get("email"),
-                password: row.get("password"),
+        let _timer = metrics::query_timer("get_account");
+        // The replica may not have the account yet right after it
+        // registered.
+        let email = &email;
+        let found = self
+            .find_fresh(|mut connection| async move {
+                find_account(&mut connection, email).await
             })
-            .fetch_one(&self.connection)
-            .await
-        {
+            .await;
+
+        match found.and_then(|account| account.ok_or(sqlx::Error::RowNotFound)) {
             Ok(account) => Ok(account),
             Err(error) => {
                 tracing::event!(tracing::Level::ERROR, "{:?}", error);
                 Err(CustomError::DatabaseQueryError(error))
             }
         } 
-    }  
+    }
 
+    /// This function replaces the password hash of an account, e.g. when
+    /// it was hashed with older argon2 settings.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "update_account_password",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn update_account_password(
+        self,
+        account_id: AccountId,
+        password: String,
+    ) -> Result<bool, CustomError> {
+        let _timer = metrics::query_timer("update_account_password");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query("UPDATE accounts SET password = $1 WHERE id = $2")
+            .bind(password)
+            .bind(account_id.0)
+            .execute(&mut *connection)
+            .await
+        {
+            Ok(result) => Ok(result.rows_affected() == 1),
+            Err(error) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", error);
+                Err(CustomError::DatabaseQueryError(error))
+            }
+        }
+    }
+
+    /// This function retrieves the account of an email address, creating
+    /// it with the given password hash when it doesn't exist yet. Used to
+    /// link single sign-on users to their account.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "get_or_create_account",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn get_or_create_account(
+        self,
+        email: String,
+        password: String,
+    ) -> Result<Account, CustomError> {
+        let _timer = metrics::query_timer("get_or_create_account");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(
+            "INSERT INTO accounts (email, password) VALUES ($1, $2)
+            ON CONFLICT ((lower(email))) DO NOTHING",
+        )
+        .bind(&email)
+        .bind(password)
+        .execute(&mut *connection)
+        .await
+        {
+            Ok(_) => {
+                // get_account takes its own connection.
+                drop(connection);
+                self.primary().get_account(email).await
+            }
+            Err(error) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", error);
+                Err(CustomError::DatabaseQueryError(error))
+            }
+        }
+    }
+
+    /// This function stores a new personal access token. Only the hash of
+    /// the token is saved.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "add_api_token",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn add_api_token(
+        self,
+        account_id: AccountId,
+        name: String,
+        scopes: Vec<Scope>,
+        token_prefix: String,
+        token_hash: String,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<ApiToken, CustomError> {
+        let scope_names: Vec<&str> = scopes.iter().map(Scope::as_str).collect();
+        let _timer = metrics::query_timer("add_api_token");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(
+            "INSERT INTO api_tokens (account_id, name, token_prefix, token_hash, scopes, expires_on)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, name, token_prefix, scopes, created_on, last_used_on, expires_on",
+        )
+        .bind(account_id.0)
+        .bind(name)
+        .bind(token_prefix)
+        .bind(token_hash)
+        .bind(scope_names)
+        .bind(expires_at)
+        .map(api_token_from_row)
+        .fetch_one(&mut *connection)
+        .await
+        {
+            Ok(token) => Ok(token),
+            Err(error) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", error);
+                Err(CustomError::DatabaseQueryError(error))
+            }
+        }
+    }
+
+    /// This function retrieves the tokens of an account which are not revoked.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "get_api_tokens",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn get_api_tokens(
+        self,
+        account_id: AccountId,
+    ) -> Result<Vec<ApiToken>, CustomError> {
+        let _timer = metrics::query_timer("get_api_tokens");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(
+            "SELECT id, name, token_prefix, scopes, created_on, last_used_on, expires_on
+            FROM api_tokens WHERE account_id = $1 AND revoked_on IS NULL ORDER BY id",
+        )
+        .bind(account_id.0)
+        .map(api_token_from_row)
+        .fetch_all(&mut *connection)
+        .await
+        {
+            Ok(tokens) => Ok(tokens),
+            Err(e) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", e);
+                Err(CustomError::DatabaseQueryError(e))
+            }
+        }
+    }
+
+    /// This function revokes a token of an account. Returns `false` when
+    /// the account has no such active token.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "revoke_api_token",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn revoke_api_token(
+        self,
+        id: i32,
+        account_id: AccountId,
+    ) -> Result<bool, CustomError> {
+        let _timer = metrics::query_timer("revoke_api_token");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(
+            "UPDATE api_tokens SET revoked_on = NOW()
+            WHERE id = $1 AND account_id = $2 AND revoked_on IS NULL",
+        )
+        .bind(id)
+        .bind(account_id.0)
+        .execute(&mut *connection)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected() > 0),
+            Err(e) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", e);
+                Err(CustomError::DatabaseQueryError(e))
+            }
+        }
+    }
+
+    /// This function looks up an active, unexpired token by its hash,
+    /// records that it has been used and returns the session it grants.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "get_api_token_session",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn get_api_token_session(
+        self,
+        token_hash: String,
+    ) -> Result<Option<Session>, CustomError> {
+        let _timer = metrics::query_timer("get_api_token_session");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(
+            "UPDATE api_tokens SET last_used_on = NOW()
+            WHERE token_hash = $1 AND revoked_on IS NULL
+            AND (expires_on IS NULL OR expires_on > NOW())
+            RETURNING id, account_id, scopes, created_on, expires_on",
+        )
+        .bind(token_hash)
+        .map(|row: PgRow| Session {
+            exp: row
+                .get::<Option<DateTime<Utc>>, _>("expires_on")
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+            account_id: AccountId(row.get("account_id")),
+            nbf: row.get("created_on"),
+            scopes: scopes_from_names(row.get("scopes")),
+            api_token_id: Some(ApiTokenId(row.get("id"))),
+            session_id: None,
+        })
+        .fetch_optional(&mut *connection)
+        .await
+        {
+            Ok(session) => Ok(session),
+            Err(e) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", e);
+                Err(CustomError::DatabaseQueryError(e))
+            }
+        }
+    }
+
+    /// This function records a new login session and returns its id.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "add_session",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn add_session(
+        self,
+        account_id: AccountId,
+        client: ClientInfo,
+        expires_at: DateTime<Utc>,
+    ) -> Result<SessionId, CustomError> {
+        let _timer = metrics::query_timer("add_session");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(
+            "INSERT INTO sessions (id, account_id, expires_on, user_agent, ip)
+            VALUES ($1, $2, $3, $4, $5) RETURNING id",
+        )
+        .bind(uuid::Uuid::new_v4())
+        .bind(account_id.0)
+        .bind(expires_at)
+        .bind(client.user_agent)
+        .bind(client.ip)
+        .map(|row: PgRow| SessionId(row.get("id")))
+        .fetch_one(&mut *connection)
+        .await
+        {
+            Ok(id) => Ok(id),
+            Err(error) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", error);
+                Err(CustomError::DatabaseQueryError(error))
+            }
+        }
+    }
+
+    /// This function retrieves the sessions of an account which are
+    /// neither revoked nor expired.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "get_sessions",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn get_sessions(
+        self,
+        account_id: AccountId,
+    ) -> Result<Vec<ActiveSession>, CustomError> {
+        let _timer = metrics::query_timer("get_sessions");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(
+            "SELECT * FROM sessions WHERE account_id = $1
+            AND revoked_on IS NULL AND expires_on > NOW()
+            ORDER BY last_seen_on DESC",
+        )
+        .bind(account_id.0)
+        .map(|row: PgRow| ActiveSession {
+            id: SessionId(row.get("id")),
+            created_at: row.get("created_on"),
+            last_seen_at: row.get("last_seen_on"),
+            expires_at: row.get("expires_on"),
+            user_agent: row.get("user_agent"),
+            ip: row.get("ip"),
+            current: false,
+        })
+        .fetch_all(&mut *connection)
+        .await
+        {
+            Ok(sessions) => Ok(sessions),
+            Err(e) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", e);
+                Err(CustomError::DatabaseQueryError(e))
+            }
+        }
+    }
+
+    /// This function revokes a session of an account, signing it out.
+    /// Returns `false` when the account has no such active session.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "revoke_session",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn revoke_session(
+        self,
+        id: SessionId,
+        account_id: AccountId,
+    ) -> Result<bool, CustomError> {
+        let _timer = metrics::query_timer("revoke_session");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(
+            "UPDATE sessions SET revoked_on = NOW()
+            WHERE id = $1 AND account_id = $2 AND revoked_on IS NULL",
+        )
+        .bind(id.0)
+        .bind(account_id.0)
+        .execute(&mut *connection)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected() > 0),
+            Err(e) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", e);
+                Err(CustomError::DatabaseQueryError(e))
+            }
+        }
+    }
+
+    /// This function records that a session was used. Returns `false`
+    /// when the session has been revoked.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "touch_session",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn touch_session(self, id: SessionId) -> Result<bool, CustomError> {
+        let _timer = metrics::query_timer("touch_session");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(
+            "UPDATE sessions SET last_seen_on = NOW()
+            WHERE id = $1 AND revoked_on IS NULL",
+        )
+        .bind(id.0)
+        .execute(&mut *connection)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected() > 0),
+            Err(e) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", e);
+                Err(CustomError::DatabaseQueryError(e))
+            }
+        }
+    }
+
+    /// This function takes a token from a rate limit bucket shared by the
+    /// instances of the server. The bucket holds at most `capacity` tokens
+    /// and gets `per_sec` back every second. Returns whether a token was
+    /// taken, and the tokens left.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "take_rate_limit_token",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn take_rate_limit_token(
+        self,
+        bucket: String,
+        capacity: f64,
+        per_sec: f64,
+    ) -> Result<(bool, f64), CustomError> {
+        let _timer = metrics::query_timer("take_rate_limit_token");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(&format!(
+            "INSERT INTO rate_limits (bucket, tokens, allowed)
+            VALUES ($1, $2 - 1, TRUE)
+            ON CONFLICT (bucket) DO UPDATE SET
+                tokens = CASE WHEN {refill} >= 1
+                    THEN {refill} - 1 ELSE {refill} END,
+                allowed = {refill} >= 1,
+                updated_at = NOW()
+            RETURNING allowed, tokens",
+            refill = RATE_LIMIT_REFILL
+        ))
+        .bind(bucket)
+        .bind(capacity)
+        .bind(per_sec)
+        .map(|row: PgRow| (row.get("allowed"), row.get("tokens")))
+        .fetch_one(&mut *connection)
+        .await
+        {
+            Ok(taken) => Ok(taken),
+            Err(e) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", e);
+                Err(CustomError::DatabaseQueryError(e))
+            }
+        }
+    }
+
+    /// This function forgets the rate limit buckets unused for `idle`,
+    /// which are full again. Returns how many were removed.
+    #[instrument(
+        skip_all,
+        fields(
+            db.system = "postgresql",
+            db.operation = "prune_rate_limits",
+            otel.kind = "client"
+        )
+    )]
+    pub async fn prune_rate_limits(self, idle: Duration) -> Result<u64, CustomError> {
+        let _timer = metrics::query_timer("prune_rate_limits");
+        let mut connection = self.primary_connection().await?;
+        match sqlx::query(
+            "DELETE FROM rate_limits
+            WHERE updated_at < NOW() - make_interval(secs => $1)",
+        )
+        .bind(idle.as_secs_f64())
+        .execute(&mut *connection)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected()),
+            Err(e) => {
+                tracing::event!(tracing::Level::ERROR, "{:?}", e);
+                Err(CustomError::DatabaseQueryError(e))
+            }
+        }
+    }
+}
+
+/// Takes a connection of a pool, recording how long it waited for it.
+async fn acquire(
+    name: &str,
+    pool: &PgPool,
+) -> Result<Connection, sqlx::Error> {
+    let start = Instant::now();
+    let connection = Box::pin(pool.acquire()).await;
+    metrics::pool_acquired(name, start.elapsed());
+    connection.map(Box::new)
+}
+
+#[cfg(test)]
+impl Store {
+    /// A store which connects only when queried, for tests which don't.
+    /// Its queries fail quickly.
+    pub fn lazy() -> Store {
+        let pool = PgPoolOptions::new()
+            .connect_timeout(Duration::from_millis(100))
+            .connect_lazy("postgres://localhost/test")
+            .unwrap();
+        Store::from_pool(pool)
+    }
+
+    /// A store on the migrated database named by `TEST_DATABASE_URL`.
+    /// Tests using it are ignored unless run with `--ignored`.
+    pub async fn test_database() -> Store {
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let connection = PgPool::connect(&url).await.unwrap();
+        sqlx::migrate!().run(&connection).await.unwrap();
+        Store::from_pool(connection)
+    }
+}
+
+/// Tokens in a shared rate limit bucket after refilling it since its
+/// last update, `$2` being its capacity and `$3` the tokens per second.
+const RATE_LIMIT_REFILL: &str = "LEAST($2, rate_limits.tokens
+    + EXTRACT(EPOCH FROM NOW() - rate_limits.updated_at)::float8 * $3)";
+
+/// Looks an account up by email, ignoring case.
+async fn find_account(
+    connection: &mut PgConnection,
+    email: &str,
+) -> Result<Option<Account>, sqlx::Error> {
+    sqlx::query("SELECT * from accounts where lower(email) = lower($1)")
+        .bind(email)
+        .map(|row: PgRow| Account {
+            id: Some(AccountId(row.get("id"))),
+            email: row.get("email"),
+            password: row.get("password"),
+            display_name: row.get("display_name"),
+        })
+        .fetch_optional(connection)
+        .await
+}
+
+/// Builds a `Question` from a row selecting `QUESTION_COLUMNS`, and
+/// `asked_by_me` when the question list is personalised.
+fn question_from_row(row: PgRow) -> Question {
+    Question {
+        id: QuestionId(row.get("id")),
+        title: row.get("title"),
+        content: row.get("content"),
+        tags: row.get("tags"),
+        version: row.get("version"),
+        created_at: row.get("created_on"),
+        updated_at: row.get("updated_on"),
+        author: Author {
+            id: AccountId(row.get("author_id")),
+            display_name: row.get("author_name"),
+        },
+        answer_count: row.get("answer_count"),
+        asked_by_me: row.try_get("asked_by_me").unwrap_or(None),
+        last_answer_at: row.get("last_answer_at"),
+    }
+}
+
+/// Builds an `Answer` from a row selecting `ANSWER_COLUMNS`.
+fn answer_from_row(row: PgRow) -> Answer {
+    Answer {
+        id: AnswerId(row.get("id")),
+        content: row.get("content"),
+        question_id: QuestionId(row.get("corresponding_question")),
+        version: row.get("version"),
+        created_at: row.get("created_on"),
+        updated_at: row.get("updated_on"),
+        author: Author {
+            id: AccountId(row.get("author_id")),
+            display_name: row.get("author_name"),
+        },
+    }
+}
+
+/// Builds an `ApiToken` from a row of the `api_tokens` table.
+fn api_token_from_row(row: PgRow) -> ApiToken {
+    ApiToken {
+        id: ApiTokenId(row.get("id")),
+        name: row.get("name"),
+        prefix: row.get("token_prefix"),
+        scopes: scopes_from_names(row.get("scopes")),
+        created_at: row.get("created_on"),
+        last_used_at: row.get("last_used_on"),
+        expires_at: row.get("expires_on"),
+    }
+}
+
+/// Converts the scope names stored in the database, skipping unknown ones.
+fn scopes_from_names(names: Vec<String>) -> Vec<Scope> {
+    names.iter().filter_map(|name| Scope::from_name(name)).collect()
+}
+
+#[cfg(test)]
+mod store_tests {
+    use super::*;
+    use std::env;
+
+    /// Connects to the databases named by `TEST_DATABASE_URL` and
+    /// `TEST_REPLICA_URL`, which tests using it need: they are ignored
+    /// unless run with `--ignored`. The replica is a separate database
+    /// which never receives the writes, standing in for a lagging replica.
+    async fn stores() -> (Store, PgPool) {
+        let replica = env::var("TEST_REPLICA_URL").expect("TEST_REPLICA_URL is not set");
+        let replica = PgPool::connect(&replica).await.unwrap();
+        sqlx::migrate!().run(&replica).await.unwrap();
+
+        (Store::test_database().await, replica)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL and TEST_REPLICA_URL, run with --ignored"]
+    async fn read_replica_until_asked_for_primary() {
+        let (store, replica) = stores().await;
+        let store = store
+            .with_replica(replica)
+            .with_read_cache(ReadCache::new(8, Duration::from_secs(60)));
+
+        let email = format!("replica-{}@test.invalid", uuid::Uuid::new_v4());
+        store
+            .clone()
+            .add_account(Account {
+                id: None,
+                email: email.clone(),
+                password: "!".to_string(),
+                display_name: None,
+            })
+            .await
+            .unwrap();
+        // Logins fall back to the primary for accounts the replica lacks.
+        let account = store.clone().get_account(email.clone()).await.unwrap();
+        let question = store
+            .clone()
+            .add_question(
+                NewQuestion {
+                    title: "replica".to_string(),
+                    content: "replica".to_string(),
+                    tags: None,
+                },
+                account.id.unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let from_replica = store
+            .clone()
+            .get_question_answers(None, 0, question.id.0)
+            .await
+            .unwrap();
+        assert!(from_replica.is_empty());
+        // Single questions fall back to the primary, their `Location` is
+        // read right after they were asked.
+        let fresh = store.clone().get_question(question.id.0, None).await.unwrap();
+        assert_eq!(fresh.map(|fresh| fresh.id), Some(question.id.clone()));
+        let fresh = store
+            .clone()
+            .primary()
+            .get_question(question.id.0, None)
+            .await
+            .unwrap();
+        assert_eq!(fresh.map(|fresh| fresh.id), Some(question.id.clone()));
+
+        let listed = |questions: Vec<Question>| {
+            questions.iter().any(|listed| listed.id == question.id)
+        };
+        let all = Some(u32::MAX);
+        assert!(!listed(store.clone().get_questions(all, 0, None).await.unwrap()));
+        // Cached replica reads aren't served to primary reads.
+        assert!(listed(
+            store.clone().primary().get_questions(all, 0, None).await.unwrap()
+        ));
+
+        sqlx::query("DELETE FROM questions WHERE id = $1")
+            .bind(question.id.0)
+            .execute(&store.connection)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM accounts WHERE lower(email) = lower($1)")
+            .bind(email)
+            .execute(&store.connection)
+            .await
+            .unwrap();
+    }
 }
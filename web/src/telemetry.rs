@@ -0,0 +1,250 @@
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, TracerProvider};
+use opentelemetry_sdk::Resource;
+use serde::Serialize;
+use tracing::field::{display, Empty};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use warp::http::{HeaderMap, Uri};
+
+use handle_errors::CustomError;
+
+/// Export of the spans to an OpenTelemetry collector, the `[trace]`
+/// section of the configuration file.
+#[derive(clap::Args, Serialize, Clone, Debug, PartialEq)]
+pub struct TraceConfig {
+    /// Base URL of an OTLP/HTTP collector, e.g. `http://localhost:4318`.
+    /// Spans are only exported when set
+    #[clap(
+        name = "trace-otlp-endpoint",
+        long = "trace-otlp-endpoint",
+        env = "OTEL_EXPORTER_OTLP_ENDPOINT"
+    )]
+    pub otlp_endpoint: Option<String>,
+    /// Service name the spans are reported under
+    #[clap(
+        name = "trace-service-name",
+        long = "trace-service-name",
+        env = "OTEL_SERVICE_NAME",
+        default_value = "web"
+    )]
+    pub service_name: String,
+    /// Share of the traces started here which are exported, from 0 to 1.
+    /// Traces started by a caller follow its sampling decision
+    #[clap(
+        name = "trace-sample-ratio",
+        long = "trace-sample-ratio",
+        env = "TRACE_SAMPLE_RATIO",
+        default_value = "1.0"
+    )]
+    pub sample_ratio: f64,
+    /// Level of the exported spans, independent of the log level
+    #[clap(
+        name = "trace-level",
+        long = "trace-level",
+        env = "TRACE_LEVEL",
+        default_value = "info"
+    )]
+    pub level: String,
+}
+
+impl TraceConfig {
+    /// Checks the endpoint is an http(s) URL and the ratio a share.
+    pub fn validate(&self) -> Result<(), CustomError> {
+        if !(0.0..=1.0).contains(&self.sample_ratio) {
+            return Err(CustomError::ConfigError(format!(
+                "trace sample ratio {} isn't between 0 and 1",
+                self.sample_ratio
+            )));
+        }
+        if let Some(endpoint) = &self.otlp_endpoint {
+            match endpoint.parse::<Uri>() {
+                Ok(uri) if matches!(uri.scheme_str(), Some("http") | Some("https")) => {}
+                _ => {
+                    return Err(CustomError::ConfigError(format!(
+                        "invalid OTLP endpoint {:?}",
+                        endpoint
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The provider batching spans to the collector, `None` when no
+    /// endpoint is set. Must be called from within the tokio runtime.
+    pub fn tracer_provider(&self) -> Result<Option<TracerProvider>, CustomError> {
+        let endpoint = match &self.otlp_endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/'),
+            None => return Ok(None),
+        };
+        let exporter = opentelemetry_otlp::new_exporter()
+            .http()
+            .with_endpoint(endpoint)
+            .build_span_exporter()
+            .map_err(|e| CustomError::ConfigError(format!("cannot export spans: {}", e)))?;
+        let config = opentelemetry_sdk::trace::config()
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                self.sample_ratio,
+            ))))
+            .with_resource(Resource::new([
+                KeyValue::new("service.name", self.service_name.clone()),
+                KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+            ]));
+
+        Ok(Some(
+            TracerProvider::builder()
+                .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+                .with_config(config)
+                .build(),
+        ))
+    }
+}
+
+/// The span of a request, like `warp::trace::request()`, continuing the
+/// trace of the caller when it sent a W3C `traceparent` header. Its
+/// `request_id` and `account_id` are recorded once known.
+pub fn request_span(info: warp::trace::Info) -> tracing::Span {
+    let span = tracing::info_span!(
+        "request",
+        remote.addr = Empty,
+        method = %info.method(),
+        path = %info.path(),
+        version = ?info.version(),
+        referer = Empty,
+        request_id = Empty,
+        account_id = Empty,
+        otel.kind = "server",
+    );
+    if let Some(remote_addr) = info.remote_addr() {
+        span.record("remote.addr", display(remote_addr));
+    }
+    if let Some(referer) = info.referer() {
+        span.record("referer", display(referer));
+    }
+    span.set_parent(parent_context(info.request_headers()));
+    span
+}
+
+/// The trace context of the `traceparent` and `tracestate` headers, empty
+/// when they are missing or invalid.
+fn parent_context(headers: &HeaderMap) -> Context {
+    TraceContextPropagator::new().extract(&opentelemetry_http::HeaderExtractor(headers))
+}
+
+#[cfg(test)]
+mod telemetry_tests {
+    use super::*;
+    use crate::store::Store;
+    use opentelemetry::trace::TracerProvider as _;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tracing::instrument::WithSubscriber;
+    use tracing::Instrument;
+    use tracing_subscriber::prelude::*;
+    use warp::Filter;
+
+    fn trace_config(endpoint: Option<&str>) -> TraceConfig {
+        TraceConfig {
+            otlp_endpoint: endpoint.map(str::to_string),
+            service_name: "web-tests".to_string(),
+            sample_ratio: 1.0,
+            level: "info".to_string(),
+        }
+    }
+
+    /// Stands in for a collector, handing over the bodies posted to it.
+    fn collector() -> (String, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let route = warp::post()
+            .and(warp::path!("v1" / "traces"))
+            .and(warp::body::bytes())
+            .map(move |body: warp::hyper::body::Bytes| {
+                let _ = sender.send(body.to_vec());
+                warp::reply()
+            });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}", address), receiver)
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn refuse_bad_settings() {
+        assert!(trace_config(None).validate().is_ok());
+        assert!(trace_config(Some("http://localhost:4318")).validate().is_ok());
+        assert!(trace_config(Some("localhost:4318")).validate().is_err());
+        let config = TraceConfig {
+            sample_ratio: 1.5,
+            ..trace_config(None)
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn continue_callers_trace() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+                .parse()
+                .unwrap(),
+        );
+        use opentelemetry::trace::TraceContextExt;
+        let context = parent_context(&headers);
+        let parent = context.span().span_context().clone();
+        assert!(parent.is_remote());
+        assert_eq!(
+            parent.trace_id().to_string(),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert!(!parent_context(&HeaderMap::new())
+            .span()
+            .span_context()
+            .is_valid());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn export_spans_to_collector() {
+        let (endpoint, mut bodies) = collector();
+        let provider = trace_config(Some(&endpoint))
+            .tracer_provider()
+            .unwrap()
+            .unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("web")));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+                .parse()
+                .unwrap(),
+        );
+        // The query fails without a database, its span is exported anyway.
+        async {
+            let request = tracing::info_span!("request");
+            request.set_parent(parent_context(&headers));
+            let _ = Store::lazy().get_questions(None, 0, None).instrument(request).await;
+        }
+        .with_subscriber(subscriber)
+        .await;
+        tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .unwrap();
+
+        let body = tokio::time::timeout(Duration::from_secs(5), bodies.recv())
+            .await
+            .expect("spans exported")
+            .unwrap();
+        assert!(contains(&body, b"web-tests"));
+        assert!(contains(&body, b"get_questions"));
+        assert!(contains(&body, b"db.operation"));
+        assert!(contains(&body, &hex::decode("0af7651916cd43dd8448eb211c80319c").unwrap()));
+    }
+}
@@ -1,5 +1,8 @@
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::types::api_token::{ApiTokenId, Scope};
 /// The Session object represents a session of token.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Session {
@@ -9,6 +12,23 @@ pub struct Session {
     pub account_id: AccountId,
     /// "Not before" time of the session.
     pub nbf: DateTime<Utc>,
+    /// Permissions of the session. Login sessions are granted every scope.
+    #[serde(default = "Scope::all")]
+    pub scopes: Vec<Scope>,
+    /// The personal access token used to authenticate, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_token_id: Option<ApiTokenId>,
+    /// The login session the token was issued for. Tokens issued before
+    /// sessions were recorded have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<SessionId>,
+}
+
+impl Session {
+    /// Checks whether the session was granted the given scope.
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
 }
 /// The Account object represents User account, including email, password and its id.
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -19,8 +39,66 @@ pub struct Account {
     pub email: String,
     /// Password of the account.
     pub password: String,
+    /// Name shown as the author of posts, optional when registering.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+}
+
+/// An account as shown to its owner, e.g. once registered. The password
+/// is left out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AccountDetails {
+    /// Unique identifier of the account.
+    pub id: AccountId,
+    /// Email address associated with the account.
+    pub email: String,
+    /// Display name of the account, the part of the email before the @
+    /// when none was chosen.
+    pub display_name: String,
+}
+
+/// The author of a question or answer, as shown to everyone.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Author {
+    /// Unique identifier of the account.
+    pub id: AccountId,
+    /// Display name of the account, the part of the email before the @
+    /// when none was chosen.
+    pub display_name: String,
 }
 
 /// Represents the unique identifier for an account.
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
-pub struct AccountId(pub i32);
\ No newline at end of file
+pub struct AccountId(pub i32);
+
+/// Represents the unique identifier for a login session.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId(pub Uuid);
+
+/// A login session as it is listed to its owner.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ActiveSession {
+    /// Unique identifier of the session.
+    pub id: SessionId,
+    /// Time of the login.
+    pub created_at: DateTime<Utc>,
+    /// Last time a token of the session was used.
+    pub last_seen_at: DateTime<Utc>,
+    /// Time the token of the session expires.
+    pub expires_at: DateTime<Utc>,
+    /// User agent of the client which logged in.
+    pub user_agent: Option<String>,
+    /// IP address of the client which logged in.
+    pub ip: Option<String>,
+    /// Whether this is the session of the request listing the sessions.
+    pub current: bool,
+}
+
+/// Information about the client sending a request, recorded with sessions.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientInfo {
+    /// Value of the `User-Agent` header.
+    pub user_agent: Option<String>,
+    /// IP address of the client.
+    pub ip: Option<String>,
+}
@@ -1,5 +1,7 @@
+use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use crate::types::account::Author;
 use crate::types::question::QuestionId;
 /// Represents the unique identifier for an answer.
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
@@ -13,6 +15,14 @@ pub struct Answer {
     pub content: String,
     /// ID of the question to which the answer belongs.
     pub question_id: QuestionId,
+    /// Bumped on every edit, sent as the `ETag` of the answer.
+    pub version: i32,
+    /// Time the answer was posted.
+    pub created_at: DateTime<Utc>,
+    /// Time of the last edit, the creation time if never edited.
+    pub updated_at: DateTime<Utc>,
+    /// Account which posted the answer.
+    pub author: Author,
 }
 
 /// Represents a new answer to be added, without its identifier.
@@ -23,3 +33,10 @@ pub struct NewAnswer {
     /// ID of the question to which the new answer belongs.
     pub question_id: QuestionId,
 }
+
+/// Represents the new content of an edited answer.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AnswerUpdate {
+    /// New content of the answer.
+    pub content: String,
+}
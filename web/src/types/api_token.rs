@@ -0,0 +1,136 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Prefix of every personal access token, used by `auth()` to tell them
+/// apart from PASETO session tokens.
+pub const API_TOKEN_PREFIX: &str = "qa_pat_";
+
+/// Longest lifetime of a personal access token, ten years.
+pub const MAX_EXPIRES_IN_DAYS: u32 = 3650;
+
+/// Represents the unique identifier for a personal access token.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ApiTokenId(pub i32);
+
+/// The permissions a personal access token can be granted.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Read resources which require authentication.
+    Read,
+    /// Create, edit and delete questions.
+    WriteQuestions,
+    /// Answer questions.
+    WriteAnswers,
+}
+
+impl Scope {
+    /// Every scope, which is what an interactive session is granted.
+    pub fn all() -> Vec<Scope> {
+        vec![Scope::Read, Scope::WriteQuestions, Scope::WriteAnswers]
+    }
+
+    /// The name under which the scope is stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::WriteQuestions => "write_questions",
+            Scope::WriteAnswers => "write_answers",
+        }
+    }
+
+    /// Parses a scope name stored in the database.
+    pub fn from_name(name: &str) -> Option<Scope> {
+        match name {
+            "read" => Some(Scope::Read),
+            "write_questions" => Some(Scope::WriteQuestions),
+            "write_answers" => Some(Scope::WriteAnswers),
+            _ => None,
+        }
+    }
+}
+
+/// A personal access token as it is listed to its owner.
+/// The secret itself is never stored, only its hash.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiToken {
+    /// Unique identifier of the token.
+    pub id: ApiTokenId,
+    /// Name given by the user, e.g. "deploy bot".
+    pub name: String,
+    /// First characters of the token, so users can recognise it.
+    pub prefix: String,
+    /// Permissions granted to the token.
+    pub scopes: Vec<Scope>,
+    /// Creation time of the token.
+    pub created_at: DateTime<Utc>,
+    /// Last time the token was used to authenticate a request.
+    pub last_used_at: Option<DateTime<Utc>>,
+    /// Optional expiration time of the token.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Represents a new token requested by a user.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NewApiToken {
+    /// Name of the new token.
+    pub name: String,
+    /// Permissions granted to the new token.
+    pub scopes: Vec<Scope>,
+    /// Number of days until the token expires, from 1 to
+    /// `MAX_EXPIRES_IN_DAYS`, `None` for no expiry.
+    #[serde(default, deserialize_with = "expires_in_days")]
+    pub expires_in_days: Option<u32>,
+}
+
+/// Refuses lifetimes out of range, so the expiry date can't overflow.
+fn expires_in_days<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u32>, D::Error> {
+    match Option::<u32>::deserialize(deserializer)? {
+        Some(days) if !(1..=MAX_EXPIRES_IN_DAYS).contains(&days) => {
+            Err(serde::de::Error::custom(format!(
+                "expires_in_days must be between 1 and {}",
+                MAX_EXPIRES_IN_DAYS
+            )))
+        }
+        days => Ok(days),
+    }
+}
+
+/// Response to a token creation: the only time the secret is revealed.
+#[derive(Serialize, Debug, Clone)]
+pub struct CreatedApiToken {
+    /// The plain-text token to put in the `Authorization` header.
+    pub token: String,
+    /// The stored metadata of the token.
+    #[serde(flatten)]
+    pub details: ApiToken,
+}
+
+#[cfg(test)]
+mod api_token_tests {
+    use super::*;
+    use warp::Filter;
+
+    #[tokio::test]
+    async fn refuse_out_of_range_expiry() {
+        let routes = warp::body::json()
+            .map(|new_token: NewApiToken| warp::reply::json(&new_token))
+            .recover(handle_errors::return_error);
+        let request = |expires_in_days: serde_json::Value| {
+            warp::test::request().method("POST").json(&serde_json::json!({
+                "name": "bot",
+                "scopes": ["read"],
+                "expires_in_days": expires_in_days,
+            }))
+        };
+
+        assert_eq!(request(30.into()).reply(&routes).await.status(), 200);
+        assert_eq!(request(serde_json::Value::Null).reply(&routes).await.status(), 200);
+        for days in [0, MAX_EXPIRES_IN_DAYS + 1, 4_000_000_000] {
+            let response = request(days.into()).reply(&routes).await;
+            assert_eq!(response.status(), 422);
+        }
+    }
+}
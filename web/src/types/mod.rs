@@ -1,4 +1,5 @@
 pub mod answer;
 pub mod pagination;
 pub mod question;
-pub mod account;
\ No newline at end of file
+pub mod account;
+pub mod api_token;
\ No newline at end of file
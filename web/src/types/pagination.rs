@@ -52,7 +52,7 @@ pub fn extract_pagination(params: HashMap<String, String>) -> Result<Pagination,
 
 #[cfg(test)] 
 mod pagination_tests { 
-    use super::{HashMap, extract_pagination, Pagination, CustomError}; 
+    use super::{HashMap, extract_pagination, Pagination}; 
 
     #[test] 
     fn valid_pagination() { 
@@ -1,4 +1,7 @@
+use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
+
+use crate::types::account::Author;
 /// This object epresents a question, including its unique identifier,
 /// title, content and tags.
 #[derive(Deserialize, Debug, Serialize, Clone)]
@@ -7,6 +10,33 @@ pub struct Question {
     pub title: String,
     pub content: String,
     pub tags: Option<Vec<String>>,
+    /// Bumped on every edit, sent as the `ETag` of the question.
+    pub version: i32,
+    /// Time the question was asked.
+    pub created_at: DateTime<Utc>,
+    /// Time of the last edit, the creation time if never edited.
+    pub updated_at: DateTime<Utc>,
+    /// Account which asked the question.
+    pub author: Author,
+    /// Number of answers to the question.
+    pub answer_count: i64,
+    /// Whether the logged in user asked the question. Only set when the
+    /// question list is requested with a token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asked_by_me: Option<bool>,
+    /// Time of the latest answer, used for `Last-Modified`.
+    #[serde(skip)]
+    pub last_answer_at: Option<DateTime<Utc>>,
+}
+
+impl Question {
+    /// Last time the question or its answer count changed.
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        match self.last_answer_at {
+            Some(last_answer_at) => self.updated_at.max(last_answer_at),
+            None => self.updated_at,
+        }
+    }
 }
 
 /// Represents the unique identifier for a question.
@@ -19,4 +49,12 @@ pub struct NewQuestion {
     pub title: String,
     pub content: String,
     pub tags: Option<Vec<String>>,
-}
\ No newline at end of file
+}
+
+/// Represents a partial edit of a question, fields left out are kept.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct QuestionPatch {
+    pub title: Option<String>,
+    pub content: Option<String>,
+    pub tags: Option<Vec<String>>,
+}